@@ -0,0 +1,232 @@
+//! Compares the code size, how many instructions the VM dispatches, and how
+//! long a run takes, for chunks before and after `Chunk::peephole`, and for
+//! increments written with and without the small-constant op codes. Each
+//! chunk is also timed on a plain `match` loop over decoded instructions, as
+//! a baseline for the VM's handler table. Both verify the chunk on every run
+//! and make the same fuel and arithmetic checks.
+//!
+//! Run with `cargo bench --bench dispatch`.
+
+#![allow(clippy::needless_return)]
+
+use std::hint::black_box;
+use std::io::{self, Write};
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+
+use rlox::instruction::{Add, Constant, DecodedInstruction, Return};
+use rlox::{ArithmeticPolicy, Chunk, InterpretResult, Value, VmConfig, VM};
+
+const RUNS: u32 = 2_000;
+
+/// Builds `0 + 1.5 + 1.5 + ...` as `count` unfused `CONSTANT, ADD` pairs.
+fn sums(count: usize) -> Chunk {
+    let mut chunk = Chunk::new();
+    chunk.write_constant(0.0, 1);
+    for _ in 0..count {
+        chunk.write_constant(1.5, 1);
+        Add::new().write(&mut chunk, 1);
+    }
+    Return::new().write(&mut chunk, 1);
+    return chunk;
+}
+
+/// Builds `0 + 1 + 1 + ...` as `count` increments, each loading its `1` with
+/// `load_one` and then adding it.
+fn increments(count: usize, load_one: fn(&mut Chunk)) -> Chunk {
+    let mut chunk = Chunk::new();
+    chunk.write_constant(0.0, 1);
+    for _ in 0..count {
+        load_one(&mut chunk);
+        Add::new().write(&mut chunk, 1);
+    }
+    Return::new().write(&mut chunk, 1);
+    return chunk;
+}
+
+fn quiet() -> VmConfig {
+    return VmConfig::new().trace(false).output(Box::new(io::sink()));
+}
+
+fn dispatches(chunk: &Arc<Chunk>) -> u64 {
+    let mut vm = VM::new(quiet().profiling(true));
+    vm.interpret(Arc::clone(chunk));
+    let profile = vm.profile().unwrap();
+    return profile.entries().iter().map(|&(_, count, _)| count).sum();
+}
+
+/// Largest magnitude checked arithmetic accepts, as in the VM.
+const MAX_EXACT_INTEGER: Value = 9_007_199_254_740_992.0;
+
+/// The checks a run makes besides executing instructions. The VM makes them
+/// on every run, so the baseline makes them too, with the values hidden from
+/// the optimizer, to keep the comparison about dispatch alone.
+#[derive(Clone, Copy)]
+struct Checks {
+    fuel: Option<u64>,
+    policy: ArithmeticPolicy,
+    checked: bool,
+}
+
+impl Checks {
+    /// Returns the checks of a VM configured by `quiet()`.
+    fn new() -> Checks {
+        return black_box(Checks {
+            fuel: None,
+            policy: ArithmeticPolicy::Ieee,
+            checked: false,
+        });
+    }
+
+    fn push(&self, stack: &mut Vec<Value>, value: Value) -> Result<(), InterpretResult> {
+        if value.is_nan() && self.policy == ArithmeticPolicy::ErrorOnNaN {
+            return Err(InterpretResult::RuntimeError);
+        }
+        if self.checked && value.abs() > MAX_EXACT_INTEGER {
+            return Err(InterpretResult::RuntimeError);
+        }
+        stack.push(value);
+        return Ok(());
+    }
+}
+
+/// Runs `chunk` by decoding each instruction and matching on it, the way
+/// the VM did before it dispatched through a handler table. Like
+/// `VM::interpret`, it verifies the chunk first and checks fuel and
+/// arithmetic results as it goes.
+fn match_loop(chunk: &Chunk, output: &mut dyn Write, checks: Checks) -> InterpretResult {
+    if chunk.verify().is_err() {
+        return InterpretResult::CompileError;
+    }
+    let mut fuel = checks.fuel;
+    let mut stack: Vec<Value> = Vec::with_capacity(256);
+    for (_, _, inst) in chunk.iter() {
+        if let Some(remaining) = fuel.as_mut() {
+            if *remaining == 0 {
+                return InterpretResult::FuelExhausted;
+            }
+            *remaining -= 1;
+        }
+        let pushed = match inst {
+            DecodedInstruction::Return(_) => {
+                writeln!(output, "{}", stack.pop().unwrap()).unwrap();
+                return InterpretResult::Ok;
+            }
+            DecodedInstruction::Constant(inst) => checks.push(&mut stack, inst.value()),
+            DecodedInstruction::ConstantLong(inst) => checks.push(&mut stack, inst.value()),
+            DecodedInstruction::Zero(_) => checks.push(&mut stack, 0.0),
+            DecodedInstruction::One(_) => checks.push(&mut stack, 1.0),
+            DecodedInstruction::Dup(_) => {
+                let a = *stack.last().unwrap();
+                checks.push(&mut stack, a)
+            }
+            DecodedInstruction::PopN(inst) => {
+                stack.truncate(stack.len() - inst.count() as usize);
+                Ok(())
+            }
+            DecodedInstruction::Negate(_) => {
+                let a = stack.pop().unwrap();
+                checks.push(&mut stack, -a)
+            }
+            DecodedInstruction::AddOne(_) => {
+                let a = stack.pop().unwrap();
+                checks.push(&mut stack, a + 1.0)
+            }
+            DecodedInstruction::Add(_) => {
+                let b = stack.pop().unwrap();
+                let a = stack.pop().unwrap();
+                checks.push(&mut stack, a + b)
+            }
+            DecodedInstruction::Subtract(_) => {
+                let b = stack.pop().unwrap();
+                let a = stack.pop().unwrap();
+                checks.push(&mut stack, a - b)
+            }
+            DecodedInstruction::Multiply(_) => {
+                let b = stack.pop().unwrap();
+                let a = stack.pop().unwrap();
+                checks.push(&mut stack, a * b)
+            }
+            DecodedInstruction::Divide(_) => {
+                let b = stack.pop().unwrap();
+                let a = stack.pop().unwrap();
+                if b == 0.0 && checks.policy == ArithmeticPolicy::ErrorOnDivisionByZero {
+                    return InterpretResult::RuntimeError;
+                }
+                checks.push(&mut stack, a / b)
+            }
+            DecodedInstruction::AddConstant(inst) => {
+                let a = stack.pop().unwrap();
+                checks.push(&mut stack, a + inst.value())
+            }
+            DecodedInstruction::SubtractConstant(inst) => {
+                let a = stack.pop().unwrap();
+                checks.push(&mut stack, a - inst.value())
+            }
+            DecodedInstruction::MultiplyConstant(inst) => {
+                let a = stack.pop().unwrap();
+                checks.push(&mut stack, a * inst.value())
+            }
+            DecodedInstruction::DivideConstant(inst) => {
+                let a = stack.pop().unwrap();
+                checks.push(&mut stack, a / inst.value())
+            }
+        };
+        if let Err(result) = pushed {
+            return result;
+        }
+    }
+    return InterpretResult::RuntimeError;
+}
+
+/// Returns the average time of a run of `chunk` on the VM, including
+/// verifying it, as `match_loop` does.
+fn time_table(chunk: &Arc<Chunk>) -> Duration {
+    let mut vm = VM::new(quiet());
+    let start = Instant::now();
+    for _ in 0..RUNS {
+        black_box(vm.interpret(Arc::clone(black_box(chunk))));
+    }
+    return start.elapsed() / RUNS;
+}
+
+/// Returns the average time of a run of `chunk` on `match_loop`.
+fn time_match(chunk: &Chunk) -> Duration {
+    let mut output = io::sink();
+    let checks = Checks::new();
+    let start = Instant::now();
+    for _ in 0..RUNS {
+        black_box(match_loop(black_box(chunk), &mut output, checks));
+    }
+    return start.elapsed() / RUNS;
+}
+
+fn report(name: &str, chunk: &Arc<Chunk>) {
+    println!(
+        "{:<12} {:>8} {:>10} {:>14} {:>14}",
+        name,
+        chunk.stats().code_bytes(),
+        dispatches(chunk),
+        time_table(chunk).as_nanos(),
+        time_match(chunk).as_nanos()
+    );
+}
+
+fn main() {
+    let unfused = sums(10_000);
+    let fused = Arc::new(unfused.peephole());
+    let unfused = Arc::new(unfused);
+    println!(
+        "{:<12} {:>8} {:>10} {:>14} {:>14}",
+        "chunk", "bytes", "dispatches", "table ns/run", "match ns/run"
+    );
+    report("unfused", &unfused);
+    report("peephole", &fused);
+
+    let pooled = increments(10_000, |chunk| Constant::new(1.0).write(chunk, 1));
+    let one = increments(10_000, |chunk| chunk.write_constant(1.0, 1));
+    let add_one = Arc::new(one.peephole());
+    report("CONSTANT 1", &Arc::new(pooled));
+    report("ONE", &Arc::new(one));
+    report("ADD_ONE", &add_one);
+}
@@ -0,0 +1,14 @@
+#![no_main]
+
+use libfuzzer_sys::fuzz_target;
+use rlox::{Chunk, VmConfig, VM};
+
+// Loading arbitrary bytes must either fail with a LoadError or produce a chunk
+// that runs without panicking.
+fuzz_target!(|data: &[u8]| {
+    if let Ok(chunk) = Chunk::from_bytes(data) {
+        let config = VmConfig::new().fuel(10_000).trace(false);
+        let mut vm = VM::new(config.output(Box::new(std::io::sink())));
+        vm.interpret(chunk);
+    }
+});
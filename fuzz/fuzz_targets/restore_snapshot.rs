@@ -0,0 +1,14 @@
+#![no_main]
+
+use libfuzzer_sys::fuzz_target;
+use rlox::{VmConfig, VM};
+
+// Restoring arbitrary bytes must either fail with a LoadError or produce a VM
+// that resumes without panicking.
+fuzz_target!(|data: &[u8]| {
+    let config = VmConfig::new().trace(false);
+    if let Ok(mut vm) = VM::restore(data, config.output(Box::new(std::io::sink()))) {
+        vm.set_fuel(Some(10_000));
+        vm.continue_();
+    }
+});
@@ -0,0 +1,1574 @@
+use std::collections::HashMap;
+use std::fmt::Write;
+
+use crate::instruction::*;
+use crate::value::{Value, ValueDisplay};
+use crate::vm::{MAX_EXACT_INTEGER, STACK_MAX};
+
+pub type Line = u16;
+
+pub struct Chunk {
+    pub(crate) instructions: Vec<u8>,
+    pub(crate) lines: Vec<Line>,
+    pub(crate) pool: Pool,
+    /// Path of the source file this chunk was compiled from, if known.
+    file: Option<String>,
+}
+
+impl Chunk {
+    pub fn new() -> Chunk {
+        return Chunk {
+            instructions: Vec::new(),
+            lines: Vec::new(),
+            pool: Pool::new(),
+            file: None,
+        };
+    }
+
+    /// Records the path of the source file this chunk was compiled from.
+    pub fn set_file(&mut self, file: &str) {
+        self.file = Some(file.to_string());
+    }
+
+    pub fn file(&self) -> Option<&str> {
+        return self.file.as_deref();
+    }
+
+    /// Returns the source line of the byte at `offset`, which may be an op
+    /// code or any of its operands.
+    pub fn line_at(&self, offset: usize) -> Line {
+        return self.lines[offset];
+    }
+
+    /// Combines separately compiled chunks into one that runs their
+    /// instructions in order. Every chunk but the last must therefore leave
+    /// its values on the stack instead of ending in `RETURN`.
+    ///
+    /// Constants are re-added to the linked chunk's pool, so a value shared
+    /// between chunks is stored once, and each load is re-encoded for its
+    /// new pool index. Lines are kept, and since a chunk has a single
+    /// source file for all of them, all `chunks` must have the same file
+    /// (or none). The linked chunk is verified before it is returned.
+    pub fn link(chunks: &[Chunk]) -> Result<Chunk, LinkError> {
+        let mut linked = Chunk::new();
+        linked.file = chunks.first().and_then(|chunk| chunk.file.clone());
+        if chunks.iter().any(|chunk| chunk.file != linked.file) {
+            return Err(LinkError::MixedFiles);
+        }
+        for chunk in chunks {
+            let mut idx = 0;
+            while idx < chunk.instructions.len() {
+                idx += copy(chunk, idx, &mut linked);
+            }
+        }
+        if let Some((offset, _)) = linked.unreachable_code() {
+            return Err(LinkError::Unreachable { offset });
+        }
+        linked.verify().map_err(LinkError::Invalid)?;
+        return Ok(linked);
+    }
+
+    /// Returns an empty chunk for the same source file, for passes that
+    /// rebuild a chunk instruction by instruction.
+    fn empty_like(&self) -> Chunk {
+        let mut chunk = Chunk::new();
+        chunk.file = self.file.clone();
+        return chunk;
+    }
+
+    /// Appends an op code and its operands to the instruction buffer. The
+    /// operand bytes are attributed to the same source line as their op
+    /// code, so that every byte of an instruction has the same line.
+    pub(crate) fn push_op(&mut self, op: u8, operands: &[u8], line: Line) {
+        self.instructions.push(op);
+        self.instructions.extend_from_slice(operands);
+        self.lines
+            .extend(std::iter::repeat_n(line, 1 + operands.len()));
+    }
+
+    pub fn disassemble(&self, name: &str) {
+        println!("=== {name} chunk ===");
+        for (offset, line, inst) in self.iter() {
+            println!("{:0>4} {:0>4} {}", offset, line, inst.disassemble());
+        }
+    }
+
+    /// Returns an iterator over the instructions in this chunk, yielding each
+    /// one's offset and line along with the decoded instruction.
+    ///
+    /// The chunk must be well-formed; see `verify`.
+    pub fn iter(&self) -> Instructions<'_> {
+        return Instructions {
+            chunk: self,
+            offset: 0,
+        };
+    }
+
+    /// Renders an instruction-level diff from this chunk to `other`, with
+    /// constant operands resolved to their values. Instructions only this
+    /// chunk has are prefixed with `-`, those only `other` has with `+`, and
+    /// shared ones with a space. Each is listed with its offset in `other`
+    /// if it is prefixed with `+`, and in this chunk otherwise.
+    ///
+    /// Instructions are the same only if their operands are bit for bit, and
+    /// constants are printed with as many digits as it takes to tell them
+    /// apart, unlike in `disassemble`.
+    ///
+    /// Both chunks must be well-formed; see `verify`.
+    pub fn diff(&self, other: &Chunk) -> String {
+        let old: Vec<(usize, (u8, u64))> = self
+            .iter()
+            .map(|(offset, _, _)| (offset, self.operands(offset)))
+            .collect();
+        let new: Vec<(usize, (u8, u64))> = other
+            .iter()
+            .map(|(offset, _, _)| (offset, other.operands(offset)))
+            .collect();
+        // Compare small ids instead of operands while searching for edits.
+        let mut ids: HashMap<(u8, u64), usize> = HashMap::new();
+        let all_ids: Vec<usize> = old
+            .iter()
+            .chain(&new)
+            .map(|&(_, operands)| {
+                let next = ids.len();
+                return *ids.entry(operands).or_insert(next);
+            })
+            .collect();
+        let (old_ids, new_ids) = all_ids.split_at(old.len());
+
+        // Chunks being compared are usually mostly the same, so only run the
+        // search on what is left between their common prefix and suffix.
+        let prefix = old_ids
+            .iter()
+            .zip(new_ids)
+            .take_while(|(a, b)| a == b)
+            .count();
+        let suffix = old_ids[prefix..]
+            .iter()
+            .rev()
+            .zip(new_ids[prefix..].iter().rev())
+            .take_while(|(a, b)| a == b)
+            .count();
+        let (old_end, new_end) = (old.len() - suffix, new.len() - suffix);
+        let mut deleted = vec![false; old.len()];
+        let mut inserted = vec![false; new.len()];
+        mark_edits(
+            &old_ids[prefix..old_end],
+            &new_ids[prefix..new_end],
+            &mut deleted[prefix..old_end],
+            &mut inserted[prefix..new_end],
+        );
+
+        let mut diff = String::new();
+        let (mut i, mut j) = (0, 0);
+        while i < old.len() || j < new.len() {
+            if i < old.len() && deleted[i] {
+                writeln!(&mut diff, "- {:0>4} {}", old[i].0, self.describe(old[i].0)).unwrap();
+                i += 1;
+            } else if j < new.len() && inserted[j] {
+                writeln!(&mut diff, "+ {:0>4} {}", new[j].0, other.describe(new[j].0)).unwrap();
+                j += 1;
+            } else {
+                writeln!(&mut diff, "  {:0>4} {}", old[i].0, self.describe(old[i].0)).unwrap();
+                i += 1;
+                j += 1;
+            }
+        }
+        return diff;
+    }
+
+    /// Returns the op code of the instruction at `offset` along with its
+    /// operand: the bits of its constant, its count, or `0` if it has none.
+    fn operands(&self, offset: usize) -> (u8, u64) {
+        let op = self.instructions[offset];
+        let operand = match self.constant_index(offset) {
+            Some(index) => self.pool.constants[index].to_bits(),
+            None if op == POPN => self.instructions[offset + 1] as u64,
+            None => 0,
+        };
+        return (op, operand);
+    }
+
+    /// Disassembles the instruction at `offset` for `diff`, printing a
+    /// constant in full whenever `ValueDisplay` would round it.
+    fn describe(&self, offset: usize) -> String {
+        let (_, inst) = DecodedInstruction::marshal(self, offset);
+        let Some(index) = self.constant_index(offset) else {
+            return inst.disassemble();
+        };
+        let value = self.pool.constants[index];
+        let shown = ValueDisplay(value).to_string();
+        let exact = shown
+            .parse::<Value>()
+            .is_ok_and(|parsed| parsed.to_bits() == value.to_bits());
+        if exact {
+            return inst.disassemble();
+        }
+        return format!("{}: {:?}", op_name(self.instructions[offset]), value);
+    }
+
+    /// Collects statistics about this chunk's bytecode: instruction counts
+    /// by op code, code size, constant pool size, and the peak stack depth
+    /// along the reachable instructions.
+    ///
+    /// The chunk must be well-formed; see `verify`.
+    pub fn stats(&self) -> Stats {
+        let mut stats = Stats {
+            counts: [0; 256],
+            code_bytes: self.instructions.len(),
+            constants: self.pool.constants.len(),
+            peak_stack_depth: 0,
+        };
+        let mut depth = 0;
+        let mut reachable = true;
+        for (offset, _, inst) in self.iter() {
+            stats.counts[self.instructions[offset] as usize] += 1;
+            if reachable {
+                let (pops, pushes) = self.stack_effect(offset).unwrap();
+                depth = depth - pops + pushes;
+                stats.peak_stack_depth = stats.peak_stack_depth.max(depth);
+            }
+            if matches!(inst, DecodedInstruction::Return(_)) {
+                reachable = false;
+            }
+        }
+        return stats;
+    }
+
+    /// Returns a copy of this chunk with constant subexpressions evaluated
+    /// ahead of time, so that e.g. `CONSTANT 2, CONSTANT 3, MULTIPLY` is
+    /// emitted as a single `CONSTANT 6`.
+    ///
+    /// Folding is opt-in: callers that want the bytecode to mirror the
+    /// source exactly (e.g. when debugging) can simply skip this pass.
+    pub fn fold_constants(&self) -> Chunk {
+        let mut folded = self.empty_like();
+        // Constants that have been read but not yet emitted, along with the
+        // line of the instruction that produced them. These are always the
+        // topmost values on the stack at this point in the program.
+        let mut pending: Vec<(Value, Line)> = Vec::new();
+        let mut idx = 0;
+        while idx < self.instructions.len() {
+            let line = self.line_at(idx);
+            let op = self.instructions[idx];
+            let consumed = match op {
+                CONSTANT => {
+                    let (c, inst) = Constant::marshal(self, idx);
+                    pending.push((inst.value(), line));
+                    c
+                }
+                CONSTANT_LONG => {
+                    let (c, inst) = ConstantLong::marshal(self, idx);
+                    pending.push((inst.value(), line));
+                    c
+                }
+                // Divisions by zero, NaN, and results that checked arithmetic
+                // would reject are left for runtime, where the VM's settings
+                // decide what happens to them.
+                ADD | SUBTRACT | MULTIPLY | DIVIDE
+                    if pending.len() >= 2 && foldable(op, &pending) =>
+                {
+                    let (b, _) = pending.pop().unwrap();
+                    let (a, _) = pending.pop().unwrap();
+                    pending.push((evaluate(op, a, b), line));
+                    1
+                }
+                ZERO => {
+                    pending.push((0.0, line));
+                    1
+                }
+                ONE => {
+                    pending.push((1.0, line));
+                    1
+                }
+                NEGATE
+                    if pending
+                        .last()
+                        .is_some_and(|&(value, _)| safe_result(-value)) =>
+                {
+                    let (value, _) = pending.pop().unwrap();
+                    pending.push((-value, line));
+                    1
+                }
+                _ => {
+                    for (value, line) in pending.drain(..) {
+                        folded.write_constant(value, line);
+                    }
+                    copy(self, idx, &mut folded)
+                }
+            };
+            idx += consumed;
+        }
+        for (value, line) in pending.drain(..) {
+            folded.write_constant(value, line);
+        }
+        return folded;
+    }
+
+    /// Returns a copy of this chunk with common instruction sequences
+    /// collapsed into fused superinstructions, so that e.g. `CONSTANT 2, ADD`
+    /// is dispatched once as `ADD_CONSTANT 2`.
+    pub fn peephole(&self) -> Chunk {
+        let mut optimized = self.empty_like();
+        let mut idx = 0;
+        while idx < self.instructions.len() {
+            let loads_one = match self.instructions[idx] {
+                ONE => true,
+                CONSTANT => Constant::marshal(self, idx).1.value() == 1.0,
+                _ => false,
+            };
+            let next = idx + self.width(idx);
+            if loads_one && next < self.instructions.len() && self.instructions[next] == ADD {
+                AddOne::new().write(&mut optimized, self.line_at(next));
+                idx = next + 1;
+                continue;
+            }
+            if self.instructions[idx] == CONSTANT && idx + 2 < self.instructions.len() {
+                let (c, inst) = Constant::marshal(self, idx);
+                if !optimized.fits_short(inst.value()) {
+                    idx += copy(self, idx, &mut optimized);
+                    continue;
+                }
+                let line = self.line_at(idx + c);
+                let fused = match self.instructions[idx + c] {
+                    ADD => {
+                        AddConstant::new(inst.value()).write(&mut optimized, line);
+                        true
+                    }
+                    SUBTRACT => {
+                        SubtractConstant::new(inst.value()).write(&mut optimized, line);
+                        true
+                    }
+                    MULTIPLY => {
+                        MultiplyConstant::new(inst.value()).write(&mut optimized, line);
+                        true
+                    }
+                    DIVIDE => {
+                        DivideConstant::new(inst.value()).write(&mut optimized, line);
+                        true
+                    }
+                    _ => false,
+                };
+                if fused {
+                    idx += c + 1;
+                    continue;
+                }
+            }
+            idx += copy(self, idx, &mut optimized);
+        }
+        return optimized;
+    }
+
+    /// Emits the most compact instruction that loads `value`: `ZERO` or
+    /// `ONE` for those values, and otherwise `CONSTANT` while its index in
+    /// the constant pool fits in one byte and `CONSTANT_LONG` after that.
+    /// Pool entries are reused for equal values.
+    pub fn write_constant(&mut self, value: Value, line: Line) {
+        // Compare bits so that `-0` keeps going through the pool.
+        if value.to_bits() == 0.0f64.to_bits() {
+            Zero::new().write(self, line);
+        } else if value == 1.0 {
+            One::new().write(self, line);
+        } else if self.fits_short(value) {
+            Constant::new(value).write(self, line);
+        } else {
+            ConstantLong::new(value).write(self, line);
+        }
+    }
+
+    /// Adds `value` to the constant pool if it isn't there yet and returns
+    /// whether its index fits in a one-byte operand.
+    fn fits_short(&mut self, value: Value) -> bool {
+        return self.pool.intern(value) <= u8::MAX as usize;
+    }
+
+    /// Adds `value` to the constant pool if it isn't there yet and returns
+    /// its index as a one-byte operand.
+    ///
+    /// Panics if the index doesn't fit; `write_constant` picks an encoding
+    /// that always does.
+    pub(crate) fn short_constant(&mut self, value: Value) -> u8 {
+        let index = self.pool.intern(value);
+        return u8::try_from(index).expect("constant index doesn't fit in one byte");
+    }
+
+    /// Returns the number of bytes taken up by the instruction at `offset`.
+    fn width(&self, offset: usize) -> usize {
+        return match self.instructions[offset] {
+            CONSTANT | ADD_CONSTANT | SUBTRACT_CONSTANT | MULTIPLY_CONSTANT | DIVIDE_CONSTANT => 2,
+            CONSTANT_LONG => 4,
+            POPN => 2,
+            _ => 1,
+        };
+    }
+
+    /// Returns how many values the instruction at `offset` pops off and
+    /// pushes onto the stack, or `None` if its op code is unknown. The whole
+    /// instruction must be in bounds.
+    fn stack_effect(&self, offset: usize) -> Option<(usize, usize)> {
+        return match self.instructions[offset] {
+            RETURN => Some((1, 0)),
+            CONSTANT | CONSTANT_LONG | ZERO | ONE => Some((0, 1)),
+            NEGATE => Some((1, 1)),
+            ADD | SUBTRACT | MULTIPLY | DIVIDE => Some((2, 1)),
+            ADD_CONSTANT | SUBTRACT_CONSTANT | MULTIPLY_CONSTANT | DIVIDE_CONSTANT => Some((1, 1)),
+            ADD_ONE => Some((1, 1)),
+            DUP => Some((1, 2)),
+            POPN => Some((self.instructions[offset + 1] as usize, 0)),
+            _ => None,
+        };
+    }
+
+    /// Returns the constant pool index operand of the instruction at
+    /// `offset`, if it has one. The whole instruction must be in bounds.
+    fn constant_index(&self, offset: usize) -> Option<usize> {
+        let operand = |n: usize| self.instructions[offset + n] as usize;
+        return match self.instructions[offset] {
+            CONSTANT | ADD_CONSTANT | SUBTRACT_CONSTANT | MULTIPLY_CONSTANT | DIVIDE_CONSTANT => {
+                Some(operand(1))
+            }
+            CONSTANT_LONG => Some(operand(1) | operand(2) << 8 | operand(3) << 16),
+            _ => None,
+        };
+    }
+
+    /// Checks that this chunk can be interpreted without panicking or reading
+    /// out of bounds: every op code is known, every instruction has all of
+    /// its operands, constant operands refer to entries in the pool, and the
+    /// stack never underflows or overflows along the reachable part of the
+    /// instruction stream.
+    pub fn verify(&self) -> Result<(), VerifyError> {
+        if self.lines.len() != self.instructions.len() {
+            return Err(VerifyError::MissingLines);
+        }
+        let mut depth = 0;
+        let mut reachable = true;
+        let mut idx = 0;
+        while idx < self.instructions.len() {
+            let op = self.instructions[idx];
+            let width = self.width(idx);
+            if idx + width > self.instructions.len() {
+                return Err(VerifyError::Truncated { offset: idx });
+            }
+            let (pops, pushes) = match self.stack_effect(idx) {
+                Some(effect) => effect,
+                None => return Err(VerifyError::UnknownOpCode { offset: idx, op }),
+            };
+            if let Some(index) = self.constant_index(idx) {
+                if index >= self.pool.constants.len() {
+                    return Err(VerifyError::ConstantOutOfBounds { offset: idx, index });
+                }
+            }
+            if reachable {
+                if depth < pops {
+                    return Err(VerifyError::StackUnderflow { offset: idx });
+                }
+                depth = depth - pops + pushes;
+                if depth > STACK_MAX {
+                    return Err(VerifyError::StackOverflow { offset: idx });
+                }
+            }
+            if op == RETURN {
+                // Nothing after an unconditional exit can execute, so there
+                // is no meaningful stack depth to track for it.
+                reachable = false;
+            }
+            idx += width;
+        }
+        return Ok(());
+    }
+
+    /// Returns the number of values on the stack right before the instruction
+    /// at `offset` runs (or once the last one has, for the end of the chunk),
+    /// or `None` if execution never gets there.
+    pub(crate) fn depth_at(&self, offset: usize) -> Option<usize> {
+        let mut depth: usize = 0;
+        for (idx, _, inst) in self.iter() {
+            if idx == offset {
+                return Some(depth);
+            }
+            if matches!(inst, DecodedInstruction::Return(_)) {
+                return None;
+            }
+            let (pops, pushes) = self.stack_effect(idx)?;
+            depth = depth.checked_sub(pops)? + pushes;
+        }
+        if offset == self.instructions.len() {
+            return Some(depth);
+        }
+        return None;
+    }
+
+    /// Serializes this chunk into a self-contained byte buffer that
+    /// `from_bytes` can load again, including its debug info (source file
+    /// and line table) so runtime errors can still point at the source.
+    pub fn to_bytes(&self) -> Vec<u8> {
+        let mut bytes = Vec::new();
+        bytes.extend_from_slice(CHUNK_MAGIC);
+        self.write_to(&mut bytes, true);
+        return bytes;
+    }
+
+    /// Like `to_bytes`, but leaves out the debug info to save space. A chunk
+    /// loaded from the result has no source file and reports every
+    /// instruction as being on line 0.
+    pub fn to_bytes_stripped(&self) -> Vec<u8> {
+        let mut bytes = Vec::new();
+        bytes.extend_from_slice(CHUNK_MAGIC);
+        self.write_to(&mut bytes, false);
+        return bytes;
+    }
+
+    /// Loads a chunk serialized by `to_bytes`. The input is untrusted: the
+    /// loaded chunk is verified before it is returned.
+    pub fn from_bytes(bytes: &[u8]) -> Result<Chunk, LoadError> {
+        let mut reader = ByteReader::new(bytes);
+        reader.expect(CHUNK_MAGIC)?;
+        let chunk = Chunk::read_from(&mut reader)?;
+        reader.finish()?;
+        return Ok(chunk);
+    }
+
+    pub(crate) fn write_to(&self, bytes: &mut Vec<u8>, debug_info: bool) {
+        bytes.extend_from_slice(&(self.instructions.len() as u32).to_le_bytes());
+        bytes.extend_from_slice(&self.instructions);
+        bytes.push(debug_info as u8);
+        if debug_info {
+            for line in &self.lines {
+                bytes.extend_from_slice(&line.to_le_bytes());
+            }
+        }
+        bytes.extend_from_slice(&(self.pool.constants.len() as u32).to_le_bytes());
+        for constant in &self.pool.constants {
+            bytes.extend_from_slice(&constant.to_le_bytes());
+        }
+        match self.file.as_ref().filter(|_| debug_info) {
+            Some(file) => {
+                bytes.push(1);
+                bytes.extend_from_slice(&(file.len() as u32).to_le_bytes());
+                bytes.extend_from_slice(file.as_bytes());
+            }
+            None => bytes.push(0),
+        }
+    }
+
+    pub(crate) fn read_from(reader: &mut ByteReader) -> Result<Chunk, LoadError> {
+        let mut chunk = Chunk::new();
+        let len = reader.u32()? as usize;
+        chunk.instructions = reader.take(len)?.to_vec();
+        if reader.flag()? {
+            for _ in 0..len {
+                chunk.lines.push(reader.u16()?);
+            }
+        } else {
+            chunk.lines = vec![0; len];
+        }
+        for _ in 0..reader.u32()? {
+            chunk.pool.add(reader.f64()?);
+        }
+        if reader.flag()? {
+            let len = reader.u32()? as usize;
+            let file = std::str::from_utf8(reader.take(len)?).map_err(|_| LoadError::Format)?;
+            chunk.file = Some(file.to_string());
+        }
+        chunk.verify().map_err(LoadError::Invalid)?;
+        return Ok(chunk);
+    }
+
+    /// Returns the offset and line of the first instruction that can never
+    /// execute because it follows an unconditional exit, if there is one.
+    ///
+    /// This is meant for reporting unreachable code to the user as a warning;
+    /// use `eliminate_dead_code` to actually drop it.
+    pub fn unreachable_code(&self) -> Option<(usize, Line)> {
+        let mut exited = false;
+        for (offset, line, inst) in self.iter() {
+            if exited {
+                return Some((offset, line));
+            }
+            exited = matches!(inst, DecodedInstruction::Return(_));
+        }
+        return None;
+    }
+
+    /// Returns a copy of this chunk without the instructions reported by
+    /// `unreachable_code`.
+    pub fn eliminate_dead_code(&self) -> Chunk {
+        let end = match self.unreachable_code() {
+            Some((offset, _)) => offset,
+            None => self.instructions.len(),
+        };
+        let mut live = self.empty_like();
+        let mut idx = 0;
+        while idx < end {
+            idx += copy(self, idx, &mut live);
+        }
+        return live;
+    }
+}
+
+pub(crate) struct Pool {
+    constants: Vec<Value>,
+    /// Index of the first entry holding each value, keyed by its bit pattern
+    /// so that `0` and `-0` stay apart and NaN can be looked up at all.
+    index: HashMap<u64, usize>,
+}
+
+impl Pool {
+    fn new() -> Pool {
+        return Pool {
+            constants: Vec::new(),
+            index: HashMap::new(),
+        };
+    }
+
+    fn add(&mut self, constant: Value) -> usize {
+        self.constants.push(constant);
+        let location = self.constants.len() - 1;
+        self.index.entry(constant.to_bits()).or_insert(location);
+        return location;
+    }
+
+    /// Returns the index of `constant` in the pool, adding it first if it
+    /// isn't there yet.
+    pub(crate) fn intern(&mut self, constant: Value) -> usize {
+        if let Some(&location) = self.index.get(&constant.to_bits()) {
+            return location;
+        }
+        return self.add(constant);
+    }
+
+    pub(crate) fn get(&self, location: usize) -> f64 {
+        return self.constants[location];
+    }
+}
+
+/// Iterator over the instructions in a `Chunk`, created by `Chunk::iter`.
+pub struct Instructions<'a> {
+    chunk: &'a Chunk,
+    offset: usize,
+}
+
+impl Iterator for Instructions<'_> {
+    type Item = (usize, Line, DecodedInstruction);
+
+    fn next(&mut self) -> Option<Self::Item> {
+        if self.offset >= self.chunk.instructions.len() {
+            return None;
+        }
+        let offset = self.offset;
+        let (consumed, inst) = DecodedInstruction::marshal(self.chunk, offset);
+        self.offset += consumed;
+        return Some((offset, self.chunk.line_at(offset), inst));
+    }
+}
+
+/// Static statistics about a chunk's bytecode, as reported by `Chunk::stats`.
+pub struct Stats {
+    counts: [usize; 256],
+    code_bytes: usize,
+    constants: usize,
+    peak_stack_depth: usize,
+}
+
+impl Stats {
+    /// Returns the name and number of occurrences of every op code in the
+    /// chunk, in op code order.
+    pub fn instructions(&self) -> Vec<(&'static str, usize)> {
+        return (0..=u8::MAX)
+            .filter(|&op| self.counts[op as usize] > 0)
+            .map(|op| (op_name(op), self.counts[op as usize]))
+            .collect();
+    }
+
+    /// Returns the size of the instruction buffer in bytes.
+    pub fn code_bytes(&self) -> usize {
+        return self.code_bytes;
+    }
+
+    /// Returns the number of entries in the constant pool.
+    pub fn constants(&self) -> usize {
+        return self.constants;
+    }
+
+    /// Returns the largest number of values the chunk can have on the stack
+    /// at once.
+    pub fn peak_stack_depth(&self) -> usize {
+        return self.peak_stack_depth;
+    }
+
+    /// Renders the statistics as a summary followed by a table of op codes.
+    pub fn report(&self) -> String {
+        let mut report = String::from("=== stats ===\n");
+        writeln!(&mut report, "code bytes: {}", self.code_bytes).unwrap();
+        writeln!(&mut report, "constants: {}", self.constants).unwrap();
+        writeln!(&mut report, "peak stack depth: {}", self.peak_stack_depth).unwrap();
+        writeln!(&mut report, "{:<18} {:>10}", "op code", "count").unwrap();
+        for (name, count) in self.instructions() {
+            writeln!(&mut report, "{:<18} {:>10}", name, count).unwrap();
+        }
+        return report;
+    }
+}
+
+/// Returns whether the binary arithmetic instruction `op` may be evaluated
+/// ahead of time on the two topmost `pending` constants. Results that the
+/// VM's arithmetic policy or checked arithmetic could reject are not.
+fn foldable(op: u8, pending: &[(Value, Line)]) -> bool {
+    let (a, b) = (pending[pending.len() - 2].0, pending[pending.len() - 1].0);
+    return safe_result(evaluate(op, a, b)) && !(op == DIVIDE && b == 0.0);
+}
+
+/// Returns whether an arithmetic result passes every arithmetic policy and
+/// checked arithmetic, so that computing it ahead of time can't hide an
+/// error.
+fn safe_result(value: Value) -> bool {
+    return !(value.is_nan() || value.abs() > MAX_EXACT_INTEGER);
+}
+
+/// Evaluates the binary arithmetic instruction `op` on `a` and `b`.
+fn evaluate(op: u8, a: Value, b: Value) -> Value {
+    return match op {
+        ADD => a + b,
+        SUBTRACT => a - b,
+        MULTIPLY => a * b,
+        _ => a / b,
+    };
+}
+
+/// Re-emits the instruction at `offset` in `from` into `to`, keeping its line
+/// number, and returns the number of bytes consumed from `from`.
+fn copy(from: &Chunk, offset: usize, to: &mut Chunk) -> usize {
+    let (consumed, inst) = DecodedInstruction::marshal(from, offset);
+    let line = from.line_at(offset);
+    // Constants can end up at different pool indices in `to`, so their
+    // encoding is picked again rather than copied.
+    match inst {
+        DecodedInstruction::Constant(inst) => to.write_constant(inst.value(), line),
+        DecodedInstruction::ConstantLong(inst) => to.write_constant(inst.value(), line),
+        DecodedInstruction::AddConstant(inst) if !to.fits_short(inst.value()) => {
+            to.write_constant(inst.value(), line);
+            Add::new().write(to, line);
+        }
+        DecodedInstruction::SubtractConstant(inst) if !to.fits_short(inst.value()) => {
+            to.write_constant(inst.value(), line);
+            Subtract::new().write(to, line);
+        }
+        DecodedInstruction::MultiplyConstant(inst) if !to.fits_short(inst.value()) => {
+            to.write_constant(inst.value(), line);
+            Multiply::new().write(to, line);
+        }
+        DecodedInstruction::DivideConstant(inst) if !to.fits_short(inst.value()) => {
+            to.write_constant(inst.value(), line);
+            Divide::new().write(to, line);
+        }
+        inst => inst.unmarshal(to, line),
+    }
+    return consumed;
+}
+
+/// Marks the elements of `old` that have to be deleted and the elements of
+/// `new` that have to be inserted to turn one into the other with as few
+/// edits as possible, leaving a longest common subsequence unmarked.
+///
+/// This is Myers' O(ND) difference algorithm in its linear-space form: find
+/// the middle snake of an optimal edit script by searching forwards from the
+/// start and backwards from the end at once, then recurse on either side of
+/// it. `deleted` and `inserted` must be as long as `old` and `new`.
+fn mark_edits(old: &[usize], new: &[usize], deleted: &mut [bool], inserted: &mut [bool]) {
+    let (n, m) = (old.len() as isize, new.len() as isize);
+    if n == 0 {
+        inserted.fill(true);
+        return;
+    }
+    if m == 0 {
+        deleted.fill(true);
+        return;
+    }
+    let total = n + m;
+    let delta = n - m;
+    // Furthest x reached on each diagonal k = x - y, indexed modulo `size`,
+    // searching forwards and backwards (from the ends of both sequences).
+    let size = 2 * n.min(m) + 2;
+    let slot = |k: isize| k.rem_euclid(size) as usize;
+    let mut forward = vec![0; size as usize];
+    let mut backward = vec![0; size as usize];
+    // Each search makes at most `d` edits in round `d`.
+    for d in 0..=(total / 2 + total % 2) {
+        for is_forward in [true, false] {
+            let (reached, opposite) = if is_forward {
+                (&mut forward, &backward)
+            } else {
+                (&mut backward, &forward)
+            };
+            let same = |x: isize, y: isize| -> bool {
+                return if is_forward {
+                    old[x as usize] == new[y as usize]
+                } else {
+                    old[(n - 1 - x) as usize] == new[(m - 1 - y) as usize]
+                };
+            };
+            let lowest = -(d - 2 * (d - m).max(0));
+            let highest = d - 2 * (d - n).max(0);
+            for k in (lowest..=highest).step_by(2) {
+                let mut x = if k == -d || k != d && reached[slot(k - 1)] < reached[slot(k + 1)] {
+                    reached[slot(k + 1)]
+                } else {
+                    reached[slot(k - 1)] + 1
+                };
+                let mut y = x - k;
+                let (start_x, start_y) = (x, y);
+                while x < n && y < m && same(x, y) {
+                    x += 1;
+                    y += 1;
+                }
+                reached[slot(k)] = x;
+                // The same diagonal as seen by the search in the other
+                // direction.
+                let other = delta - k;
+                let overlaps = (total % 2 == 1) == is_forward
+                    && (-(d - is_forward as isize)..=d - is_forward as isize).contains(&other)
+                    && reached[slot(k)] + opposite[slot(other)] >= n;
+                if !overlaps {
+                    continue;
+                }
+                // The middle snake runs from (x0, y0) to (x1, y1).
+                let (edits, x0, y0, x1, y1) = if is_forward {
+                    (2 * d - 1, start_x, start_y, x, y)
+                } else {
+                    (2 * d, n - x, m - y, n - start_x, m - start_y)
+                };
+                if edits > 1 || x0 != x1 && y0 != y1 {
+                    let (x0, y0, x1, y1) = (x0 as usize, y0 as usize, x1 as usize, y1 as usize);
+                    let (deleted_before, deleted_after) = deleted.split_at_mut(x1);
+                    let (inserted_before, inserted_after) = inserted.split_at_mut(y1);
+                    mark_edits(
+                        &old[..x0],
+                        &new[..y0],
+                        &mut deleted_before[..x0],
+                        &mut inserted_before[..y0],
+                    );
+                    mark_edits(&old[x1..], &new[y1..], deleted_after, inserted_after);
+                } else if m > n {
+                    // A single edit with nothing in common after it: the
+                    // longer sequence just has one more element at its end.
+                    inserted[n as usize..].fill(true);
+                } else if m < n {
+                    deleted[m as usize..].fill(true);
+                }
+                return;
+            }
+        }
+    }
+}
+
+/// Describes why `Chunk::verify` rejected a chunk. Offsets refer to the op
+/// code of the offending instruction.
+#[derive(Debug)]
+pub enum VerifyError {
+    /// The line table doesn't cover every byte of the instruction buffer.
+    MissingLines,
+    UnknownOpCode {
+        offset: usize,
+        op: u8,
+    },
+    /// The instruction runs past the end of the instruction buffer.
+    Truncated {
+        offset: usize,
+    },
+    ConstantOutOfBounds {
+        offset: usize,
+        index: usize,
+    },
+    StackUnderflow {
+        offset: usize,
+    },
+    StackOverflow {
+        offset: usize,
+    },
+}
+
+impl std::fmt::Display for VerifyError {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        return match self {
+            VerifyError::MissingLines => write!(f, "line table is incomplete"),
+            VerifyError::UnknownOpCode { offset, op } => {
+                write!(f, "{:0>4}: unknown op code {}", offset, op)
+            }
+            VerifyError::Truncated { offset } => {
+                write!(f, "{:0>4}: instruction is missing operands", offset)
+            }
+            VerifyError::ConstantOutOfBounds { offset, index } => {
+                write!(f, "{:0>4}: constant {} is not in the pool", offset, index)
+            }
+            VerifyError::StackUnderflow { offset } => write!(f, "{:0>4}: stack underflow", offset),
+            VerifyError::StackOverflow { offset } => write!(f, "{:0>4}: stack overflow", offset),
+        };
+    }
+}
+
+const CHUNK_MAGIC: &[u8] = b"RLOXC\x02";
+
+/// Describes why serialized bytecode or a VM snapshot couldn't be loaded.
+#[derive(Debug)]
+pub enum LoadError {
+    /// The input isn't in the expected format or version.
+    Format,
+    /// The input ended early or has trailing bytes.
+    Truncated,
+    /// The chunk failed verification.
+    Invalid(VerifyError),
+    /// The saved execution state doesn't fit the saved chunk.
+    State,
+}
+
+impl std::fmt::Display for LoadError {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        return match self {
+            LoadError::Format => write!(f, "unrecognized format"),
+            LoadError::Truncated => write!(f, "unexpected length"),
+            LoadError::Invalid(err) => write!(f, "invalid chunk: {}", err),
+            LoadError::State => write!(f, "inconsistent VM state"),
+        };
+    }
+}
+
+/// Describes why `Chunk::link` couldn't combine a set of chunks.
+#[derive(Debug)]
+pub enum LinkError {
+    /// The chunks were compiled from different source files.
+    MixedFiles,
+    /// A chunk other than the last returns, so the instruction at `offset`
+    /// in the linked chunk and everything after it would never run.
+    Unreachable { offset: usize },
+    /// The linked chunk failed verification, e.g. because a chunk pops
+    /// values that the chunks before it don't leave on the stack.
+    Invalid(VerifyError),
+}
+
+impl std::fmt::Display for LinkError {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        return match self {
+            LinkError::MixedFiles => write!(f, "chunks come from different source files"),
+            LinkError::Unreachable { offset } => {
+                write!(f, "{:0>4}: unreachable after an earlier RETURN", offset)
+            }
+            LinkError::Invalid(err) => write!(f, "invalid linked chunk: {}", err),
+        };
+    }
+}
+
+/// Reads little-endian values off the front of a byte slice.
+pub(crate) struct ByteReader<'a> {
+    bytes: &'a [u8],
+}
+
+impl<'a> ByteReader<'a> {
+    pub(crate) fn new(bytes: &'a [u8]) -> ByteReader<'a> {
+        return ByteReader { bytes };
+    }
+
+    fn take(&mut self, len: usize) -> Result<&'a [u8], LoadError> {
+        if len > self.bytes.len() {
+            return Err(LoadError::Truncated);
+        }
+        let (taken, rest) = self.bytes.split_at(len);
+        self.bytes = rest;
+        return Ok(taken);
+    }
+
+    pub(crate) fn expect(&mut self, magic: &[u8]) -> Result<(), LoadError> {
+        if self.take(magic.len()).map_err(|_| LoadError::Format)? != magic {
+            return Err(LoadError::Format);
+        }
+        return Ok(());
+    }
+
+    pub(crate) fn finish(&self) -> Result<(), LoadError> {
+        if !self.bytes.is_empty() {
+            return Err(LoadError::Truncated);
+        }
+        return Ok(());
+    }
+
+    pub(crate) fn u8(&mut self) -> Result<u8, LoadError> {
+        return Ok(self.take(1)?[0]);
+    }
+
+    /// Reads a boolean stored as a `0` or `1` byte, rejecting anything else.
+    pub(crate) fn flag(&mut self) -> Result<bool, LoadError> {
+        return match self.u8()? {
+            0 => Ok(false),
+            1 => Ok(true),
+            _ => Err(LoadError::Format),
+        };
+    }
+
+    fn u16(&mut self) -> Result<u16, LoadError> {
+        return Ok(u16::from_le_bytes(self.take(2)?.try_into().unwrap()));
+    }
+
+    pub(crate) fn u32(&mut self) -> Result<u32, LoadError> {
+        return Ok(u32::from_le_bytes(self.take(4)?.try_into().unwrap()));
+    }
+
+    pub(crate) fn u64(&mut self) -> Result<u64, LoadError> {
+        return Ok(u64::from_le_bytes(self.take(8)?.try_into().unwrap()));
+    }
+
+    pub(crate) fn f64(&mut self) -> Result<f64, LoadError> {
+        return Ok(f64::from_le_bytes(self.take(8)?.try_into().unwrap()));
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_util::{dispatches, run};
+    use crate::vm::{ArithmeticPolicy, InterpretResult, VmConfig};
+
+    /// Builds a chunk from `build` followed by a `RETURN`, all on line 1.
+    fn returning(build: impl FnOnce(&mut Chunk)) -> Chunk {
+        let mut chunk = Chunk::new();
+        build(&mut chunk);
+        Return::new().write(&mut chunk, 1);
+        return chunk;
+    }
+
+    fn ops(chunk: &Chunk) -> Vec<String> {
+        return chunk
+            .iter()
+            .map(|(_, _, inst)| inst.disassemble())
+            .collect();
+    }
+
+    fn binary(a: Value, b: Value, write_op: fn(&mut Chunk)) -> Chunk {
+        return returning(|chunk| {
+            chunk.write_constant(a, 1);
+            chunk.write_constant(b, 1);
+            write_op(chunk);
+        });
+    }
+
+    #[test]
+    fn fold_constants_shrinks_constant_arithmetic() {
+        let chunk = returning(|chunk| {
+            chunk.write_constant(2.0, 1);
+            chunk.write_constant(3.0, 1);
+            Multiply::new().write(chunk, 1);
+            chunk.write_constant(4.0, 1);
+            Add::new().write(chunk, 1);
+            Negate::new().write(chunk, 1);
+        });
+        let folded = chunk.fold_constants();
+        assert_eq!(ops(&folded), ["CONSTANT: -10", "RETURN"]);
+        assert!(folded.stats().code_bytes() < chunk.stats().code_bytes());
+        assert_eq!(run(folded, VmConfig::new()), run(chunk, VmConfig::new()));
+    }
+
+    #[test]
+    fn fold_constants_keeps_division_by_zero() {
+        let chunk = binary(1.0, 2.0, |chunk| Divide::new().write(chunk, 1));
+        assert_eq!(ops(&chunk.fold_constants()), ["CONSTANT: 0.5", "RETURN"]);
+
+        let chunk = binary(1.0, 0.0, |chunk| Divide::new().write(chunk, 1));
+        assert_eq!(ops(&chunk.fold_constants()), ops(&chunk));
+        let config = VmConfig::new().arithmetic_policy(ArithmeticPolicy::ErrorOnDivisionByZero);
+        let (_, result) = run(chunk.fold_constants(), config);
+        assert_eq!(result, InterpretResult::RuntimeError);
+    }
+
+    #[test]
+    fn fold_constants_keeps_nan_results() {
+        let chunk = binary(f64::INFINITY, -f64::INFINITY, |chunk| {
+            Add::new().write(chunk, 1);
+        });
+        let folded = chunk.fold_constants();
+        assert_eq!(ops(&folded), ops(&chunk));
+        let config = VmConfig::new().arithmetic_policy(ArithmeticPolicy::ErrorOnNaN);
+        assert_eq!(run(folded, config).1, InterpretResult::RuntimeError);
+    }
+
+    #[test]
+    fn fold_constants_keeps_checked_arithmetic_failures() {
+        let overflow = binary(1e308, 10.0, |chunk| Multiply::new().write(chunk, 1));
+        let inexact = binary(MAX_EXACT_INTEGER, 2.0, |chunk| {
+            Multiply::new().write(chunk, 1)
+        });
+        let negated = |value: Value| {
+            return returning(|chunk| {
+                chunk.write_constant(value, 1);
+                Negate::new().write(chunk, 1);
+            });
+        };
+        for chunk in [overflow, inexact, negated(1e20), negated(f64::INFINITY)] {
+            let folded = chunk.fold_constants();
+            assert_eq!(ops(&folded), ops(&chunk));
+            let config = VmConfig::new().checked_arithmetic(true);
+            assert_eq!(run(folded, config).1, InterpretResult::RuntimeError);
+        }
+    }
+
+    #[test]
+    fn peephole_fuses_constant_operands() {
+        fn build(chunk: &mut Chunk) {
+            chunk.write_constant(10.0, 1);
+            chunk.write_constant(2.5, 1);
+            Add::new().write(chunk, 1);
+            chunk.write_constant(4.0, 1);
+            Multiply::new().write(chunk, 1);
+            chunk.write_constant(3.0, 1);
+            Subtract::new().write(chunk, 1);
+            chunk.write_constant(2.0, 1);
+            Divide::new().write(chunk, 1);
+        }
+        let chunk = returning(build);
+        let optimized = chunk.peephole();
+        assert_eq!(
+            ops(&optimized),
+            [
+                "CONSTANT: 10",
+                "ADD_CONSTANT: 2.5",
+                "MULTIPLY_CONSTANT: 4",
+                "SUBTRACT_CONSTANT: 3",
+                "DIVIDE_CONSTANT: 2",
+                "RETURN",
+            ]
+        );
+        assert_eq!(dispatches(returning(build)), 10);
+        assert_eq!(dispatches(returning(build).peephole()), 6);
+        assert_eq!(run(optimized, VmConfig::new()), run(chunk, VmConfig::new()));
+    }
+
+    #[test]
+    fn peephole_keeps_unfusable_sequences() {
+        let chunk = returning(|chunk| {
+            chunk.write_constant(2.0, 1);
+            Negate::new().write(chunk, 1);
+            chunk.write_constant(3.0, 1);
+            Dup::new().write(chunk, 1);
+            Add::new().write(chunk, 1);
+            Add::new().write(chunk, 1);
+        });
+        assert_eq!(ops(&chunk.peephole()), ops(&chunk));
+    }
+
+    #[test]
+    #[should_panic]
+    fn marshal_is_bounds_checked() {
+        PopN::marshal(&Chunk::new(), 0);
+    }
+
+    #[test]
+    fn round_trips_through_bytes() {
+        let mut chunk = returning(|chunk| {
+            for n in 0..300 {
+                chunk.write_constant(n as Value + 2.0, 2);
+                PopN::new(1).write(chunk, 3);
+            }
+            chunk.write_constant(-0.0, 4);
+            chunk.write_constant(0.5, 4);
+            Add::new().write(chunk, 5);
+        });
+        chunk.set_file("main.lox");
+
+        let loaded = Chunk::from_bytes(&chunk.to_bytes()).unwrap();
+        assert_eq!(loaded.file(), Some("main.lox"));
+        let listing = |chunk: &Chunk| -> Vec<(usize, Line, String)> {
+            return chunk
+                .iter()
+                .map(|(offset, line, inst)| (offset, line, inst.disassemble()))
+                .collect();
+        };
+        assert_eq!(listing(&loaded), listing(&chunk));
+        assert_eq!(loaded.to_bytes(), chunk.to_bytes());
+
+        let stripped = Chunk::from_bytes(&chunk.to_bytes_stripped()).unwrap();
+        assert_eq!(stripped.file(), None);
+        assert!(stripped.iter().all(|(_, line, _)| line == 0));
+        assert_eq!(ops(&stripped), ops(&chunk));
+        assert_eq!(run(stripped, VmConfig::new()), run(chunk, VmConfig::new()));
+    }
+
+    #[test]
+    fn diff_marks_changed_instructions() {
+        let old = returning(|chunk| {
+            chunk.write_constant(1.0, 1);
+            chunk.write_constant(2.0, 1);
+            Add::new().write(chunk, 1);
+        });
+        let new = returning(|chunk| {
+            chunk.write_constant(1.0, 1);
+            chunk.write_constant(3.0, 1);
+            Add::new().write(chunk, 1);
+            Negate::new().write(chunk, 1);
+        });
+        assert_eq!(
+            old.diff(&new),
+            concat!(
+                "  0000 ONE\n",
+                "- 0001 CONSTANT: 2\n",
+                "+ 0001 CONSTANT: 3\n",
+                "  0003 ADD\n",
+                "+ 0004 NEGATE\n",
+                "  0004 RETURN\n",
+            )
+        );
+        assert!(old.diff(&old).lines().all(|line| line.starts_with("  ")));
+    }
+
+    #[test]
+    fn diff_scales_to_large_chunks() {
+        let build = |changed: bool| {
+            let mut chunk = Chunk::new();
+            for n in 0..40_000 {
+                if changed && n % 10_000 == 0 {
+                    Negate::new().write(&mut chunk, 1);
+                } else if n % 2 == 0 {
+                    Zero::new().write(&mut chunk, 1);
+                } else {
+                    One::new().write(&mut chunk, 1);
+                }
+            }
+            return chunk;
+        };
+        let diff = build(false).diff(&build(true));
+        assert_eq!(diff.lines().filter(|line| line.starts_with('-')).count(), 4);
+        assert_eq!(diff.lines().filter(|line| line.starts_with('+')).count(), 4);
+        assert_eq!(diff.lines().count(), 40_004);
+    }
+
+    #[test]
+    fn mark_edits_keeps_a_longest_common_subsequence() {
+        let mut seed: u32 = 1;
+        let mut random = |bound: u32| -> usize {
+            seed = seed.wrapping_mul(1_103_515_245).wrapping_add(12_345);
+            return ((seed >> 16) % bound) as usize;
+        };
+        for _ in 0..1_000 {
+            let old: Vec<usize> = (0..random(16)).map(|_| random(4)).collect();
+            let new: Vec<usize> = (0..random(16)).map(|_| random(4)).collect();
+            let mut deleted = vec![false; old.len()];
+            let mut inserted = vec![false; new.len()];
+            mark_edits(&old, &new, &mut deleted, &mut inserted);
+
+            let kept = |items: &[usize], marked: &[bool]| -> Vec<usize> {
+                return items
+                    .iter()
+                    .zip(marked)
+                    .filter(|(_, &marked)| !marked)
+                    .map(|(&item, _)| item)
+                    .collect();
+            };
+            let common = kept(&old, &deleted);
+            assert_eq!(common, kept(&new, &inserted));
+
+            let mut longest = vec![vec![0; new.len() + 1]; old.len() + 1];
+            for i in (0..old.len()).rev() {
+                for j in (0..new.len()).rev() {
+                    longest[i][j] = if old[i] == new[j] {
+                        longest[i + 1][j + 1] + 1
+                    } else {
+                        longest[i + 1][j].max(longest[i][j + 1])
+                    };
+                }
+            }
+            assert_eq!(common.len(), longest[0][0], "{:?} -> {:?}", old, new);
+        }
+    }
+
+    #[test]
+    fn from_bytes_rejects_unknown_flags() {
+        let mut chunk = returning(|chunk| chunk.write_constant(2.0, 1));
+        chunk.set_file("main.lox");
+        let bytes = chunk.to_bytes();
+        // The debug info flag follows the instruction buffer, and the file
+        // flag follows the constant pool.
+        let debug_info = CHUNK_MAGIC.len() + 4 + chunk.instructions.len();
+        let file = debug_info + 1 + 2 * chunk.lines.len() + 4 + 8 * chunk.pool.constants.len();
+        for (offset, flag) in [(debug_info, 1), (file, 1)] {
+            assert_eq!(bytes[offset], flag);
+            for value in [2, 255] {
+                let mut corrupted = bytes.clone();
+                corrupted[offset] = value;
+                assert!(matches!(
+                    Chunk::from_bytes(&corrupted),
+                    Err(LoadError::Format)
+                ));
+            }
+        }
+
+        let stripped = chunk.to_bytes_stripped();
+        let mut corrupted = stripped.clone();
+        *corrupted.last_mut().unwrap() = 2;
+        assert!(Chunk::from_bytes(&stripped).is_ok());
+        assert!(matches!(
+            Chunk::from_bytes(&corrupted),
+            Err(LoadError::Format)
+        ));
+    }
+
+    #[test]
+    fn from_bytes_verifies_the_chunk() {
+        // CONSTANT 0, RETURN.
+        let chunk = returning(|chunk| chunk.write_constant(2.0, 1));
+        let bytes = chunk.to_bytes();
+        let code = CHUNK_MAGIC.len() + 4;
+        assert_eq!(bytes[code..code + 3], [CONSTANT, 0, RETURN]);
+        let corrupt = |offset: usize, value: u8| {
+            let mut corrupted = bytes.clone();
+            corrupted[code + offset] = value;
+            return Chunk::from_bytes(&corrupted);
+        };
+
+        assert!(matches!(
+            corrupt(2, 200),
+            Err(LoadError::Invalid(VerifyError::UnknownOpCode {
+                offset: 2,
+                op: 200
+            }))
+        ));
+        assert!(matches!(
+            corrupt(2, CONSTANT),
+            Err(LoadError::Invalid(VerifyError::Truncated { offset: 2 }))
+        ));
+        assert!(matches!(
+            corrupt(1, 5),
+            Err(LoadError::Invalid(VerifyError::ConstantOutOfBounds {
+                offset: 0,
+                index: 5
+            }))
+        ));
+        assert!(matches!(
+            Chunk::from_bytes(&bytes[..bytes.len() - 1]),
+            Err(LoadError::Truncated)
+        ));
+
+        // Serialized chunks always have a line per byte, so an incomplete
+        // line table can only come from building one directly.
+        let mut missing_lines = returning(|chunk| chunk.write_constant(2.0, 1));
+        missing_lines.lines.pop();
+        assert!(matches!(
+            missing_lines.verify(),
+            Err(VerifyError::MissingLines)
+        ));
+    }
+
+    #[test]
+    fn stats_count_op_codes_and_reachable_stack_depth() {
+        let mut chunk = Chunk::new();
+        chunk.write_constant(2.0, 1);
+        chunk.write_constant(3.0, 1);
+        Dup::new().write(&mut chunk, 1);
+        Multiply::new().write(&mut chunk, 1);
+        Add::new().write(&mut chunk, 1);
+        Return::new().write(&mut chunk, 1);
+        // Unreachable, so it doesn't count towards the peak depth.
+        for _ in 0..4 {
+            Dup::new().write(&mut chunk, 2);
+        }
+        Return::new().write(&mut chunk, 2);
+
+        let stats = chunk.stats();
+        assert_eq!(
+            stats.instructions(),
+            [
+                ("RETURN", 2),
+                ("CONSTANT", 2),
+                ("ADD", 1),
+                ("MULTIPLY", 1),
+                ("DUP", 5)
+            ]
+        );
+        assert_eq!(stats.code_bytes(), 13);
+        assert_eq!(stats.constants(), 2);
+        assert_eq!(stats.peak_stack_depth(), 3);
+        assert_eq!(
+            stats.report(),
+            "=== stats ===\n\
+             code bytes: 13\n\
+             constants: 2\n\
+             peak stack depth: 3\n\
+             op code                 count\n\
+             RETURN                      2\n\
+             CONSTANT                    2\n\
+             ADD                         1\n\
+             MULTIPLY                    1\n\
+             DUP                         5\n"
+        );
+    }
+
+    #[test]
+    fn write_constant_uses_small_constant_op_codes() {
+        let chunk = returning(|chunk| {
+            chunk.write_constant(0.0, 1);
+            chunk.write_constant(-0.0, 1);
+            Add::new().write(chunk, 1);
+            chunk.write_constant(1.0, 1);
+            Add::new().write(chunk, 1);
+            chunk.write_constant(2.0, 1);
+            Add::new().write(chunk, 1);
+        });
+        assert_eq!(
+            ops(&chunk),
+            [
+                "ZERO",
+                "CONSTANT: -0",
+                "ADD",
+                "ONE",
+                "ADD",
+                "CONSTANT: 2",
+                "ADD",
+                "RETURN"
+            ]
+        );
+        // Only -0 and 2 go through the constant pool.
+        assert_eq!(chunk.stats().constants(), 2);
+        assert_eq!(run(chunk, VmConfig::new()).0, "3\n");
+    }
+
+    #[test]
+    fn peephole_fuses_increments() {
+        fn build(chunk: &mut Chunk) {
+            Zero::new().write(chunk, 1);
+            One::new().write(chunk, 1);
+            Add::new().write(chunk, 1);
+            Constant::new(1.0).write(chunk, 1);
+            Add::new().write(chunk, 1);
+        }
+        let chunk = returning(build);
+        let optimized = chunk.peephole();
+        assert_eq!(ops(&optimized), ["ZERO", "ADD_ONE", "ADD_ONE", "RETURN"]);
+        assert!(optimized.stats().code_bytes() < chunk.stats().code_bytes());
+        assert_eq!(dispatches(returning(build)), 6);
+        assert_eq!(dispatches(returning(build).peephole()), 4);
+        assert_eq!(run(optimized, VmConfig::new()), run(chunk, VmConfig::new()));
+    }
+
+    #[test]
+    fn diff_tells_close_constants_apart() {
+        let old = returning(|chunk| chunk.write_constant(0.1, 1));
+        let new = returning(|chunk| chunk.write_constant(0.1000001, 1));
+        assert_eq!(ops(&old), ops(&new));
+        assert_eq!(
+            old.diff(&new),
+            concat!(
+                "- 0000 CONSTANT: 0.1\n",
+                "+ 0000 CONSTANT: 0.1000001\n",
+                "  0002 RETURN\n",
+            )
+        );
+    }
+
+    #[test]
+    fn link_shares_constants_between_chunks() {
+        let mut first = Chunk::new();
+        first.write_constant(2.5, 1);
+        let second = returning(|chunk| {
+            chunk.write_constant(2.5, 1);
+            Add::new().write(chunk, 1);
+        });
+        let linked = Chunk::link(&[first, second]).unwrap();
+        assert_eq!(
+            ops(&linked),
+            ["CONSTANT: 2.5", "CONSTANT: 2.5", "ADD", "RETURN"]
+        );
+        assert_eq!(linked.stats().constants(), 1);
+        assert_eq!(run(linked, VmConfig::new()).0, "5\n");
+    }
+
+    #[test]
+    fn link_re_encodes_constants_past_one_byte() {
+        let mut first = Chunk::new();
+        for n in 0..256 {
+            first.write_constant(n as Value + 2.0, 1);
+            PopN::new(1).write(&mut first, 1);
+        }
+        let second = returning(|chunk| {
+            chunk.write_constant(7.5, 2);
+            AddConstant::new(8.5).write(chunk, 2);
+        });
+        assert_eq!(
+            ops(&second),
+            ["CONSTANT: 7.5", "ADD_CONSTANT: 8.5", "RETURN"]
+        );
+        let linked = Chunk::link(&[first, second]).unwrap();
+        assert_eq!(
+            ops(&linked)[512..],
+            ["CONSTANT_LONG: 7.5", "CONSTANT_LONG: 8.5", "ADD", "RETURN"]
+        );
+        assert!(linked
+            .iter()
+            .skip(512)
+            .take(3)
+            .all(|(_, line, _)| line == 2));
+        assert_eq!(run(linked, VmConfig::new()).0, "16\n");
+    }
+
+    #[test]
+    fn link_rejects_chunks_that_do_not_fit_together() {
+        let mut main = returning(|chunk| chunk.write_constant(1.0, 1));
+        main.set_file("main.lox");
+        let mut library = Chunk::new();
+        library.write_constant(2.0, 1);
+        library.set_file("library.lox");
+        assert!(matches!(
+            Chunk::link(&[library, main]),
+            Err(LinkError::MixedFiles)
+        ));
+
+        let early = returning(|chunk| chunk.write_constant(1.0, 1));
+        let late = returning(|chunk| chunk.write_constant(2.0, 1));
+        assert!(matches!(
+            Chunk::link(&[early, late]),
+            Err(LinkError::Unreachable { offset: 2 })
+        ));
+
+        let mut first = Chunk::new();
+        first.write_constant(1.0, 1);
+        let second = returning(|chunk| Add::new().write(chunk, 1));
+        assert!(matches!(
+            Chunk::link(&[first, second]),
+            Err(LinkError::Invalid(VerifyError::StackUnderflow {
+                offset: 1
+            }))
+        ));
+    }
+
+    #[test]
+    fn eliminate_dead_code_drops_code_after_return() {
+        let mut chunk = Chunk::new();
+        chunk.write_constant(1.5, 1);
+        Negate::new().write(&mut chunk, 2);
+        Return::new().write(&mut chunk, 3);
+        chunk.write_constant(2.5, 4);
+        Return::new().write(&mut chunk, 5);
+        assert_eq!(chunk.unreachable_code(), Some((4, 4)));
+
+        let live = chunk.eliminate_dead_code();
+        assert_eq!(live.unreachable_code(), None);
+        let listing: Vec<(usize, Line, String)> = live
+            .iter()
+            .map(|(offset, line, inst)| (offset, line, inst.disassemble()))
+            .collect();
+        assert_eq!(
+            listing,
+            [
+                (0, 1, "CONSTANT: 1.5".to_string()),
+                (2, 2, "NEGATE".to_string()),
+                (3, 3, "RETURN".to_string()),
+            ]
+        );
+        assert_eq!(run(live, VmConfig::new()), run(chunk, VmConfig::new()));
+    }
+
+    #[test]
+    fn eliminate_dead_code_keeps_chunks_without_return() {
+        let mut chunk = Chunk::new();
+        chunk.write_constant(1.5, 1);
+        Dup::new().write(&mut chunk, 2);
+        assert_eq!(chunk.unreachable_code(), None);
+        let live = chunk.eliminate_dead_code();
+        assert_eq!(live.to_bytes(), chunk.to_bytes());
+    }
+}
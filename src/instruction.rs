@@ -0,0 +1,746 @@
+use crate::chunk::{Chunk, Line};
+use crate::value::ValueDisplay;
+
+pub(crate) const RETURN: u8 = 1;
+pub(crate) const CONSTANT: u8 = 2;
+pub(crate) const NEGATE: u8 = 3;
+pub(crate) const ADD: u8 = 4;
+pub(crate) const SUBTRACT: u8 = 5;
+pub(crate) const MULTIPLY: u8 = 6;
+pub(crate) const DIVIDE: u8 = 7;
+pub(crate) const ADD_CONSTANT: u8 = 8;
+pub(crate) const SUBTRACT_CONSTANT: u8 = 9;
+pub(crate) const MULTIPLY_CONSTANT: u8 = 10;
+pub(crate) const DIVIDE_CONSTANT: u8 = 11;
+pub(crate) const CONSTANT_LONG: u8 = 12;
+pub(crate) const DUP: u8 = 13;
+pub(crate) const POPN: u8 = 14;
+pub(crate) const ZERO: u8 = 15;
+pub(crate) const ONE: u8 = 16;
+pub(crate) const ADD_ONE: u8 = 17;
+
+/// Returns the name of the given op code as used by the disassembler.
+pub(crate) fn op_name(op: u8) -> &'static str {
+    return match op {
+        RETURN => "RETURN",
+        CONSTANT => "CONSTANT",
+        CONSTANT_LONG => "CONSTANT_LONG",
+        DUP => "DUP",
+        POPN => "POPN",
+        ZERO => "ZERO",
+        ONE => "ONE",
+        ADD_ONE => "ADD_ONE",
+        NEGATE => "NEGATE",
+        ADD => "ADD",
+        SUBTRACT => "SUBTRACT",
+        MULTIPLY => "MULTIPLY",
+        DIVIDE => "DIVIDE",
+        ADD_CONSTANT => "ADD_CONSTANT",
+        SUBTRACT_CONSTANT => "SUBTRACT_CONSTANT",
+        MULTIPLY_CONSTANT => "MULTIPLY_CONSTANT",
+        DIVIDE_CONSTANT => "DIVIDE_CONSTANT",
+        _ => "UNKNOWN",
+    };
+}
+
+pub trait Instruction {
+    /// Marshals a new instance of this instruction from a `Chunk` starting at a
+    /// byte offset and returns the number of bytes consumed and the new
+    /// instance.
+    ///
+    /// The offset must point to the op code byte.
+    fn marshal(chunk: &Chunk, offset: usize) -> (usize, Self)
+    where
+        Self: Sized;
+
+    /// Unmarshals this instruction into bytes in a `Chunk` with an associated
+    /// line number.
+    fn unmarshal(&self, chunk: &mut Chunk, line: Line);
+
+    /// Returns a debug string that describes this instruction and an assocaited
+    /// line number.
+    fn disassemble(&self) -> String;
+}
+
+/// Any instruction, as decoded from a `Chunk` by `Chunk::iter`.
+pub enum DecodedInstruction {
+    Return(Return),
+    Constant(Constant),
+    ConstantLong(ConstantLong),
+    Negate(Negate),
+    Add(Add),
+    Subtract(Subtract),
+    Multiply(Multiply),
+    Divide(Divide),
+    AddConstant(AddConstant),
+    SubtractConstant(SubtractConstant),
+    MultiplyConstant(MultiplyConstant),
+    DivideConstant(DivideConstant),
+    Dup(Dup),
+    PopN(PopN),
+    Zero(Zero),
+    One(One),
+    AddOne(AddOne),
+}
+
+impl Instruction for DecodedInstruction {
+    fn marshal(chunk: &Chunk, offset: usize) -> (usize, Self) {
+        return match chunk.instructions[offset] {
+            RETURN => {
+                let (c, inst) = Return::marshal(chunk, offset);
+                (c, DecodedInstruction::Return(inst))
+            }
+            CONSTANT => {
+                let (c, inst) = Constant::marshal(chunk, offset);
+                (c, DecodedInstruction::Constant(inst))
+            }
+            CONSTANT_LONG => {
+                let (c, inst) = ConstantLong::marshal(chunk, offset);
+                (c, DecodedInstruction::ConstantLong(inst))
+            }
+            NEGATE => {
+                let (c, inst) = Negate::marshal(chunk, offset);
+                (c, DecodedInstruction::Negate(inst))
+            }
+            ADD => {
+                let (c, inst) = Add::marshal(chunk, offset);
+                (c, DecodedInstruction::Add(inst))
+            }
+            SUBTRACT => {
+                let (c, inst) = Subtract::marshal(chunk, offset);
+                (c, DecodedInstruction::Subtract(inst))
+            }
+            MULTIPLY => {
+                let (c, inst) = Multiply::marshal(chunk, offset);
+                (c, DecodedInstruction::Multiply(inst))
+            }
+            DIVIDE => {
+                let (c, inst) = Divide::marshal(chunk, offset);
+                (c, DecodedInstruction::Divide(inst))
+            }
+            ADD_CONSTANT => {
+                let (c, inst) = AddConstant::marshal(chunk, offset);
+                (c, DecodedInstruction::AddConstant(inst))
+            }
+            SUBTRACT_CONSTANT => {
+                let (c, inst) = SubtractConstant::marshal(chunk, offset);
+                (c, DecodedInstruction::SubtractConstant(inst))
+            }
+            MULTIPLY_CONSTANT => {
+                let (c, inst) = MultiplyConstant::marshal(chunk, offset);
+                (c, DecodedInstruction::MultiplyConstant(inst))
+            }
+            DIVIDE_CONSTANT => {
+                let (c, inst) = DivideConstant::marshal(chunk, offset);
+                (c, DecodedInstruction::DivideConstant(inst))
+            }
+            DUP => {
+                let (c, inst) = Dup::marshal(chunk, offset);
+                (c, DecodedInstruction::Dup(inst))
+            }
+            POPN => {
+                let (c, inst) = PopN::marshal(chunk, offset);
+                (c, DecodedInstruction::PopN(inst))
+            }
+            ZERO => {
+                let (c, inst) = Zero::marshal(chunk, offset);
+                (c, DecodedInstruction::Zero(inst))
+            }
+            ONE => {
+                let (c, inst) = One::marshal(chunk, offset);
+                (c, DecodedInstruction::One(inst))
+            }
+            ADD_ONE => {
+                let (c, inst) = AddOne::marshal(chunk, offset);
+                (c, DecodedInstruction::AddOne(inst))
+            }
+            _ => panic!("unknown op code"),
+        };
+    }
+
+    fn unmarshal(&self, chunk: &mut Chunk, line: Line) {
+        match self {
+            DecodedInstruction::Return(inst) => inst.unmarshal(chunk, line),
+            DecodedInstruction::Constant(inst) => inst.unmarshal(chunk, line),
+            DecodedInstruction::ConstantLong(inst) => inst.unmarshal(chunk, line),
+            DecodedInstruction::Negate(inst) => inst.unmarshal(chunk, line),
+            DecodedInstruction::Add(inst) => inst.unmarshal(chunk, line),
+            DecodedInstruction::Subtract(inst) => inst.unmarshal(chunk, line),
+            DecodedInstruction::Multiply(inst) => inst.unmarshal(chunk, line),
+            DecodedInstruction::Divide(inst) => inst.unmarshal(chunk, line),
+            DecodedInstruction::AddConstant(inst) => inst.unmarshal(chunk, line),
+            DecodedInstruction::SubtractConstant(inst) => inst.unmarshal(chunk, line),
+            DecodedInstruction::MultiplyConstant(inst) => inst.unmarshal(chunk, line),
+            DecodedInstruction::DivideConstant(inst) => inst.unmarshal(chunk, line),
+            DecodedInstruction::Dup(inst) => inst.unmarshal(chunk, line),
+            DecodedInstruction::PopN(inst) => inst.unmarshal(chunk, line),
+            DecodedInstruction::Zero(inst) => inst.unmarshal(chunk, line),
+            DecodedInstruction::One(inst) => inst.unmarshal(chunk, line),
+            DecodedInstruction::AddOne(inst) => inst.unmarshal(chunk, line),
+        }
+    }
+
+    fn disassemble(&self) -> String {
+        return match self {
+            DecodedInstruction::Return(inst) => inst.disassemble(),
+            DecodedInstruction::Constant(inst) => inst.disassemble(),
+            DecodedInstruction::ConstantLong(inst) => inst.disassemble(),
+            DecodedInstruction::Negate(inst) => inst.disassemble(),
+            DecodedInstruction::Add(inst) => inst.disassemble(),
+            DecodedInstruction::Subtract(inst) => inst.disassemble(),
+            DecodedInstruction::Multiply(inst) => inst.disassemble(),
+            DecodedInstruction::Divide(inst) => inst.disassemble(),
+            DecodedInstruction::AddConstant(inst) => inst.disassemble(),
+            DecodedInstruction::SubtractConstant(inst) => inst.disassemble(),
+            DecodedInstruction::MultiplyConstant(inst) => inst.disassemble(),
+            DecodedInstruction::DivideConstant(inst) => inst.disassemble(),
+            DecodedInstruction::Dup(inst) => inst.disassemble(),
+            DecodedInstruction::PopN(inst) => inst.disassemble(),
+            DecodedInstruction::Zero(inst) => inst.disassemble(),
+            DecodedInstruction::One(inst) => inst.disassemble(),
+            DecodedInstruction::AddOne(inst) => inst.disassemble(),
+        };
+    }
+}
+
+pub struct Return {}
+
+impl Return {
+    pub fn new() -> Return {
+        return Return {};
+    }
+
+    pub fn write(&self, chunk: &mut Chunk, line: Line) {
+        self.unmarshal(chunk, line);
+    }
+}
+
+impl Instruction for Return {
+    fn marshal(_chunk: &Chunk, _offset: usize) -> (usize, Self) {
+        return (1, Return {});
+    }
+
+    fn unmarshal(&self, chunk: &mut Chunk, line: Line) {
+        chunk.push_op(RETURN, &[], line);
+    }
+
+    fn disassemble(&self) -> String {
+        "RETURN".to_string()
+    }
+}
+
+pub struct Negate {}
+
+impl Negate {
+    pub fn new() -> Negate {
+        return Negate {};
+    }
+
+    pub fn write(&self, chunk: &mut Chunk, line: Line) {
+        self.unmarshal(chunk, line);
+    }
+}
+
+impl Instruction for Negate {
+    fn marshal(_chunk: &Chunk, _offset: usize) -> (usize, Self) {
+        return (1, Negate {});
+    }
+
+    fn unmarshal(&self, chunk: &mut Chunk, line: Line) {
+        chunk.push_op(NEGATE, &[], line);
+    }
+
+    fn disassemble(&self) -> String {
+        "NEGATE".to_string()
+    }
+}
+
+/// Pushes `0` without going through the constant pool.
+pub struct Zero {}
+
+impl Zero {
+    pub fn new() -> Zero {
+        return Zero {};
+    }
+
+    pub fn write(&self, chunk: &mut Chunk, line: Line) {
+        self.unmarshal(chunk, line);
+    }
+}
+
+impl Instruction for Zero {
+    fn marshal(_chunk: &Chunk, _offset: usize) -> (usize, Self) {
+        return (1, Zero {});
+    }
+
+    fn unmarshal(&self, chunk: &mut Chunk, line: Line) {
+        chunk.push_op(ZERO, &[], line);
+    }
+
+    fn disassemble(&self) -> String {
+        "ZERO".to_string()
+    }
+}
+
+/// Pushes `1` without going through the constant pool.
+pub struct One {}
+
+impl One {
+    pub fn new() -> One {
+        return One {};
+    }
+
+    pub fn write(&self, chunk: &mut Chunk, line: Line) {
+        self.unmarshal(chunk, line);
+    }
+}
+
+impl Instruction for One {
+    fn marshal(_chunk: &Chunk, _offset: usize) -> (usize, Self) {
+        return (1, One {});
+    }
+
+    fn unmarshal(&self, chunk: &mut Chunk, line: Line) {
+        chunk.push_op(ONE, &[], line);
+    }
+
+    fn disassemble(&self) -> String {
+        "ONE".to_string()
+    }
+}
+
+/// Adds `1` to the topmost value, as in `CONSTANT 1, ADD`.
+pub struct AddOne {}
+
+impl AddOne {
+    pub fn new() -> AddOne {
+        return AddOne {};
+    }
+
+    pub fn write(&self, chunk: &mut Chunk, line: Line) {
+        self.unmarshal(chunk, line);
+    }
+}
+
+impl Instruction for AddOne {
+    fn marshal(_chunk: &Chunk, _offset: usize) -> (usize, Self) {
+        return (1, AddOne {});
+    }
+
+    fn unmarshal(&self, chunk: &mut Chunk, line: Line) {
+        chunk.push_op(ADD_ONE, &[], line);
+    }
+
+    fn disassemble(&self) -> String {
+        "ADD_ONE".to_string()
+    }
+}
+
+/// Pushes a copy of the topmost value.
+pub struct Dup {}
+
+impl Dup {
+    pub fn new() -> Dup {
+        return Dup {};
+    }
+
+    pub fn write(&self, chunk: &mut Chunk, line: Line) {
+        self.unmarshal(chunk, line);
+    }
+}
+
+impl Instruction for Dup {
+    fn marshal(_chunk: &Chunk, _offset: usize) -> (usize, Self) {
+        return (1, Dup {});
+    }
+
+    fn unmarshal(&self, chunk: &mut Chunk, line: Line) {
+        chunk.push_op(DUP, &[], line);
+    }
+
+    fn disassemble(&self) -> String {
+        "DUP".to_string()
+    }
+}
+
+/// Pops a fixed number of values at once, e.g. the locals of a block that
+/// is being exited.
+pub struct PopN {
+    count: u8,
+}
+
+impl PopN {
+    pub fn new(count: u8) -> PopN {
+        return PopN { count };
+    }
+
+    pub fn count(&self) -> u8 {
+        return self.count;
+    }
+
+    pub fn write(&self, chunk: &mut Chunk, line: Line) {
+        self.unmarshal(chunk, line);
+    }
+}
+
+impl Instruction for PopN {
+    fn marshal(chunk: &Chunk, offset: usize) -> (usize, Self) {
+        return (
+            2,
+            PopN {
+                count: chunk.instructions[offset + 1],
+            },
+        );
+    }
+
+    fn unmarshal(&self, chunk: &mut Chunk, line: Line) {
+        chunk.push_op(POPN, &[self.count], line);
+    }
+
+    fn disassemble(&self) -> String {
+        format!("POPN: {}", self.count)
+    }
+}
+
+pub struct Add {}
+
+impl Add {
+    pub fn new() -> Add {
+        return Add {};
+    }
+
+    pub fn write(&self, chunk: &mut Chunk, line: Line) {
+        self.unmarshal(chunk, line);
+    }
+}
+
+impl Instruction for Add {
+    fn marshal(_chunk: &Chunk, _offset: usize) -> (usize, Self) {
+        return (1, Add {});
+    }
+
+    fn unmarshal(&self, chunk: &mut Chunk, line: Line) {
+        chunk.push_op(ADD, &[], line);
+    }
+
+    fn disassemble(&self) -> String {
+        "ADD".to_string()
+    }
+}
+
+pub struct Subtract {}
+
+impl Subtract {
+    pub fn new() -> Subtract {
+        return Subtract {};
+    }
+
+    pub fn write(&self, chunk: &mut Chunk, line: Line) {
+        self.unmarshal(chunk, line);
+    }
+}
+
+impl Instruction for Subtract {
+    fn marshal(_chunk: &Chunk, _offset: usize) -> (usize, Self) {
+        return (1, Subtract {});
+    }
+
+    fn unmarshal(&self, chunk: &mut Chunk, line: Line) {
+        chunk.push_op(SUBTRACT, &[], line);
+    }
+
+    fn disassemble(&self) -> String {
+        "SUBTRACT".to_string()
+    }
+}
+
+pub struct Multiply {}
+
+impl Multiply {
+    pub fn new() -> Multiply {
+        return Multiply {};
+    }
+
+    pub fn write(&self, chunk: &mut Chunk, line: Line) {
+        self.unmarshal(chunk, line);
+    }
+}
+
+impl Instruction for Multiply {
+    fn marshal(_chunk: &Chunk, _offset: usize) -> (usize, Self) {
+        return (1, Multiply {});
+    }
+
+    fn unmarshal(&self, chunk: &mut Chunk, line: Line) {
+        chunk.push_op(MULTIPLY, &[], line);
+    }
+
+    fn disassemble(&self) -> String {
+        "MULTIPLY".to_string()
+    }
+}
+
+pub struct Divide {}
+
+impl Divide {
+    pub fn new() -> Divide {
+        return Divide {};
+    }
+
+    pub fn write(&self, chunk: &mut Chunk, line: Line) {
+        self.unmarshal(chunk, line);
+    }
+}
+
+impl Instruction for Divide {
+    fn marshal(_chunk: &Chunk, _offset: usize) -> (usize, Self) {
+        return (1, Divide {});
+    }
+
+    fn unmarshal(&self, chunk: &mut Chunk, line: Line) {
+        chunk.push_op(DIVIDE, &[], line);
+    }
+
+    fn disassemble(&self) -> String {
+        "DIVIDE".to_string()
+    }
+}
+
+pub struct Constant {
+    value: f64,
+}
+
+impl Constant {
+    pub fn new(value: f64) -> Constant {
+        return Constant { value };
+    }
+
+    pub fn value(&self) -> f64 {
+        return self.value;
+    }
+
+    pub fn write(&self, chunk: &mut Chunk, line: Line) {
+        self.unmarshal(chunk, line);
+    }
+}
+
+impl Instruction for Constant {
+    fn marshal(chunk: &Chunk, offset: usize) -> (usize, Self) {
+        let loc = chunk.instructions[offset + 1];
+        return (
+            2,
+            Constant {
+                value: chunk.pool.get(loc.into()),
+            },
+        );
+    }
+
+    fn unmarshal(&self, chunk: &mut Chunk, line: Line) {
+        let index = chunk.short_constant(self.value);
+        chunk.push_op(CONSTANT, &[index], line);
+    }
+
+    fn disassemble(&self) -> String {
+        format!("CONSTANT: {}", ValueDisplay(self.value))
+    }
+}
+
+/// Loads a constant whose pool index doesn't fit in `Constant`'s one-byte
+/// operand, using a three-byte little-endian index instead.
+pub struct ConstantLong {
+    value: f64,
+}
+
+impl ConstantLong {
+    pub fn new(value: f64) -> ConstantLong {
+        return ConstantLong { value };
+    }
+
+    pub fn value(&self) -> f64 {
+        return self.value;
+    }
+
+    pub fn write(&self, chunk: &mut Chunk, line: Line) {
+        self.unmarshal(chunk, line);
+    }
+}
+
+impl Instruction for ConstantLong {
+    fn marshal(chunk: &Chunk, offset: usize) -> (usize, Self) {
+        let loc = chunk.instructions[offset + 1] as usize
+            | (chunk.instructions[offset + 2] as usize) << 8
+            | (chunk.instructions[offset + 3] as usize) << 16;
+        return (
+            4,
+            ConstantLong {
+                value: chunk.pool.get(loc),
+            },
+        );
+    }
+
+    fn unmarshal(&self, chunk: &mut Chunk, line: Line) {
+        let index = chunk.pool.intern(self.value);
+        assert!(index < 1 << 24, "constant pool is full");
+        chunk.push_op(CONSTANT_LONG, &index.to_le_bytes()[..3], line);
+    }
+
+    fn disassemble(&self) -> String {
+        format!("CONSTANT_LONG: {}", ValueDisplay(self.value))
+    }
+}
+
+pub struct AddConstant {
+    value: f64,
+}
+
+impl AddConstant {
+    pub fn new(value: f64) -> AddConstant {
+        return AddConstant { value };
+    }
+
+    pub fn value(&self) -> f64 {
+        return self.value;
+    }
+
+    pub fn write(&self, chunk: &mut Chunk, line: Line) {
+        self.unmarshal(chunk, line);
+    }
+}
+
+impl Instruction for AddConstant {
+    fn marshal(chunk: &Chunk, offset: usize) -> (usize, Self) {
+        let loc = chunk.instructions[offset + 1];
+        return (
+            2,
+            AddConstant {
+                value: chunk.pool.get(loc.into()),
+            },
+        );
+    }
+
+    fn unmarshal(&self, chunk: &mut Chunk, line: Line) {
+        let index = chunk.short_constant(self.value);
+        chunk.push_op(ADD_CONSTANT, &[index], line);
+    }
+
+    fn disassemble(&self) -> String {
+        format!("ADD_CONSTANT: {}", ValueDisplay(self.value))
+    }
+}
+
+pub struct SubtractConstant {
+    value: f64,
+}
+
+impl SubtractConstant {
+    pub fn new(value: f64) -> SubtractConstant {
+        return SubtractConstant { value };
+    }
+
+    pub fn value(&self) -> f64 {
+        return self.value;
+    }
+
+    pub fn write(&self, chunk: &mut Chunk, line: Line) {
+        self.unmarshal(chunk, line);
+    }
+}
+
+impl Instruction for SubtractConstant {
+    fn marshal(chunk: &Chunk, offset: usize) -> (usize, Self) {
+        let loc = chunk.instructions[offset + 1];
+        return (
+            2,
+            SubtractConstant {
+                value: chunk.pool.get(loc.into()),
+            },
+        );
+    }
+
+    fn unmarshal(&self, chunk: &mut Chunk, line: Line) {
+        let index = chunk.short_constant(self.value);
+        chunk.push_op(SUBTRACT_CONSTANT, &[index], line);
+    }
+
+    fn disassemble(&self) -> String {
+        format!("SUBTRACT_CONSTANT: {}", ValueDisplay(self.value))
+    }
+}
+
+pub struct MultiplyConstant {
+    value: f64,
+}
+
+impl MultiplyConstant {
+    pub fn new(value: f64) -> MultiplyConstant {
+        return MultiplyConstant { value };
+    }
+
+    pub fn value(&self) -> f64 {
+        return self.value;
+    }
+
+    pub fn write(&self, chunk: &mut Chunk, line: Line) {
+        self.unmarshal(chunk, line);
+    }
+}
+
+impl Instruction for MultiplyConstant {
+    fn marshal(chunk: &Chunk, offset: usize) -> (usize, Self) {
+        let loc = chunk.instructions[offset + 1];
+        return (
+            2,
+            MultiplyConstant {
+                value: chunk.pool.get(loc.into()),
+            },
+        );
+    }
+
+    fn unmarshal(&self, chunk: &mut Chunk, line: Line) {
+        let index = chunk.short_constant(self.value);
+        chunk.push_op(MULTIPLY_CONSTANT, &[index], line);
+    }
+
+    fn disassemble(&self) -> String {
+        format!("MULTIPLY_CONSTANT: {}", ValueDisplay(self.value))
+    }
+}
+
+pub struct DivideConstant {
+    value: f64,
+}
+
+impl DivideConstant {
+    pub fn new(value: f64) -> DivideConstant {
+        return DivideConstant { value };
+    }
+
+    pub fn value(&self) -> f64 {
+        return self.value;
+    }
+
+    pub fn write(&self, chunk: &mut Chunk, line: Line) {
+        self.unmarshal(chunk, line);
+    }
+}
+
+impl Instruction for DivideConstant {
+    fn marshal(chunk: &Chunk, offset: usize) -> (usize, Self) {
+        let loc = chunk.instructions[offset + 1];
+        return (
+            2,
+            DivideConstant {
+                value: chunk.pool.get(loc.into()),
+            },
+        );
+    }
+
+    fn unmarshal(&self, chunk: &mut Chunk, line: Line) {
+        let index = chunk.short_constant(self.value);
+        chunk.push_op(DIVIDE_CONSTANT, &[index], line);
+    }
+
+    fn disassemble(&self) -> String {
+        format!("DIVIDE_CONSTANT: {}", ValueDisplay(self.value))
+    }
+}
@@ -0,0 +1,13 @@
+#![allow(clippy::needless_return, clippy::new_without_default)]
+
+pub mod chunk;
+pub mod instruction;
+pub mod value;
+pub mod vm;
+
+#[cfg(test)]
+mod test_util;
+
+pub use chunk::{Chunk, Line, LinkError, LoadError, Stats, VerifyError};
+pub use value::{Value, ValueDisplay};
+pub use vm::{ArithmeticPolicy, InterpretResult, StepOutcome, VmConfig, VmHook, VM};
@@ -1,14 +1,67 @@
-mod vm;
+#![allow(clippy::needless_return)]
+
+use rlox::instruction::{Add, Constant, Divide, Negate, Return};
+use rlox::{Chunk, VmConfig, VM};
 
 fn main() {
-    let mut chunk = vm::Chunk::new();
-    vm::Constant::new(1.2).write(&mut chunk, 100);
-    vm::Constant::new(3.4).write(&mut chunk, 100);
-    vm::Add::new().write(&mut chunk, 100);
-    vm::Constant::new(5.6).write(&mut chunk, 100);
-    vm::Divide::new().write(&mut chunk, 100);
-    vm::Negate::new().write(&mut chunk, 100);
-    vm::Return::new().write(&mut chunk, 101);
-    let mut vm = vm::VM::new(chunk);
-    vm.interpret();
+    let args: Vec<String> = std::env::args().collect();
+    if args.get(1).map(String::as_str) == Some("bytecode-diff") {
+        bytecode_diff(&args[2..]);
+        return;
+    }
+    let coverage = args.iter().any(|arg| arg == "--coverage");
+    let stats = args.iter().any(|arg| arg == "--stats");
+
+    let mut chunk = Chunk::new();
+    Constant::new(1.2).write(&mut chunk, 100);
+    Constant::new(3.4).write(&mut chunk, 100);
+    Add::new().write(&mut chunk, 100);
+    Constant::new(5.6).write(&mut chunk, 100);
+    Divide::new().write(&mut chunk, 100);
+    Negate::new().write(&mut chunk, 100);
+    Return::new().write(&mut chunk, 101);
+    if let Some((offset, line)) = chunk.unreachable_code() {
+        eprintln!(
+            "[line {}] Warning: unreachable code at {:0>4}.",
+            line, offset
+        );
+    }
+    if stats {
+        eprint!("{}", chunk.stats().report());
+    }
+    let mut vm = VM::new(VmConfig::new().coverage(coverage));
+    vm.interpret(chunk);
+    if let Some(coverage) = vm.coverage() {
+        eprint!("{}", coverage.report());
+    }
+}
+
+/// Prints an instruction-level diff between two chunks serialized with
+/// `Chunk::to_bytes`.
+fn bytecode_diff(paths: &[String]) {
+    if paths.len() != 2 {
+        eprintln!("Usage: rlox bytecode-diff old.loxc new.loxc");
+        std::process::exit(64);
+    }
+    let old = load(&paths[0]);
+    let new = load(&paths[1]);
+    print!("{}", old.diff(&new));
+}
+
+/// Loads a serialized chunk, exiting with an error if that fails.
+fn load(path: &str) -> Chunk {
+    let bytes = match std::fs::read(path) {
+        Ok(bytes) => bytes,
+        Err(err) => {
+            eprintln!("Could not read \"{}\": {}", path, err);
+            std::process::exit(74);
+        }
+    };
+    return match Chunk::from_bytes(&bytes) {
+        Ok(chunk) => chunk,
+        Err(err) => {
+            eprintln!("Could not load \"{}\": {}", path, err);
+            std::process::exit(65);
+        }
+    };
 }
@@ -0,0 +1,46 @@
+use std::io;
+use std::sync::{Arc, Mutex};
+
+use crate::chunk::Chunk;
+use crate::vm::{InterpretResult, VmConfig, VM};
+
+/// An output sink whose contents can still be read after it has been handed
+/// to a VM.
+#[derive(Clone, Default)]
+pub(crate) struct Output(Arc<Mutex<Vec<u8>>>);
+
+impl Output {
+    pub(crate) fn text(&self) -> String {
+        return String::from_utf8(self.0.lock().unwrap().clone()).unwrap();
+    }
+}
+
+impl io::Write for Output {
+    fn write(&mut self, bytes: &[u8]) -> io::Result<usize> {
+        self.0.lock().unwrap().extend_from_slice(bytes);
+        return Ok(bytes.len());
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        return Ok(());
+    }
+}
+
+/// Runs `chunk` to completion on a VM set up by `config`, without tracing,
+/// and returns what it printed along with how it finished.
+pub(crate) fn run(chunk: Chunk, config: VmConfig) -> (String, InterpretResult) {
+    let output = Output::default();
+    let mut vm = VM::new(config.trace(false).output(Box::new(output.clone())));
+    let result = vm.interpret(chunk);
+    return (output.text(), result);
+}
+
+/// Runs `chunk` to completion and returns how many instructions the VM
+/// dispatched.
+pub(crate) fn dispatches(chunk: Chunk) -> u64 {
+    let config = VmConfig::new().trace(false).profiling(true);
+    let mut vm = VM::new(config.output(Box::new(io::sink())));
+    vm.interpret(chunk);
+    let profile = vm.profile().unwrap();
+    return profile.entries().iter().map(|&(_, count, _)| count).sum();
+}
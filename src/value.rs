@@ -0,0 +1,71 @@
+pub type Value = f64;
+
+/// Formats a `Value` the way clox prints it: numbers use C's `%g` (six
+/// significant digits, no trailing zeros, exponent notation only for very
+/// large or small magnitudes), so `3.0` prints as `3` and `0.1 + 0.2` as `0.3`.
+pub struct ValueDisplay(pub Value);
+
+impl std::fmt::Display for ValueDisplay {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        let n = self.0;
+        if n.is_nan() {
+            return write!(f, "nan");
+        }
+        if n.is_infinite() {
+            return write!(f, "{}", if n > 0.0 { "inf" } else { "-inf" });
+        }
+        if n == 0.0 {
+            return write!(f, "{}", if n.is_sign_negative() { "-0" } else { "0" });
+        }
+        // Round to six significant digits first: like printf, the choice of
+        // notation depends on the exponent after rounding.
+        let scientific = format!("{:.5e}", n);
+        let (mantissa, exponent) = scientific.split_once('e').unwrap();
+        let exponent: i32 = exponent.parse().unwrap();
+        if !(-4..6).contains(&exponent) {
+            let sign = if exponent < 0 { '-' } else { '+' };
+            return write!(
+                f,
+                "{}e{}{:02}",
+                trim_fraction(mantissa),
+                sign,
+                exponent.abs()
+            );
+        }
+        let fixed = format!("{:.*}", (5 - exponent) as usize, n);
+        return write!(f, "{}", trim_fraction(&fixed));
+    }
+}
+
+/// Strips trailing zeros, and then a trailing decimal point, from a formatted
+/// number.
+fn trim_fraction(number: &str) -> &str {
+    if !number.contains('.') {
+        return number;
+    }
+    return number.trim_end_matches('0').trim_end_matches('.');
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn formats_like_printf_g() {
+        let cases = [
+            (1e-5, "1e-05"),
+            (0.0001, "0.0001"),
+            (100000.0, "100000"),
+            (1e6, "1e+06"),
+            (123456789.0, "1.23457e+08"),
+            (999999.5, "1e+06"),
+            (-0.0, "-0"),
+            (f64::INFINITY, "inf"),
+            (f64::NEG_INFINITY, "-inf"),
+            (f64::NAN, "nan"),
+        ];
+        for (value, expected) in cases {
+            assert_eq!(ValueDisplay(value).to_string(), expected, "{:?}", value);
+        }
+    }
+}
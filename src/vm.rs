@@ -1,40 +1,765 @@
+use std::collections::{BTreeMap, BTreeSet};
 use std::fmt::Write;
-
+use std::io;
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+
+use crate::chunk::{ByteReader, Chunk, Line, LoadError, VerifyError};
+use crate::instruction::*;
+use crate::value::{Value, ValueDisplay};
+
+/// A virtual machine executing chunks.
+///
+/// A VM owns all of its mutable state: the stack and any installed hooks.
+/// Chunks are immutable once loaded and held through an `Arc`, so the same
+/// chunk can be run by several VMs. Nothing is shared between VMs through
+/// globals (there is no process-wide intern table), so independent VMs can
+/// run concurrently in one process and a VM can be moved to another thread.
 pub struct VM {
-    chunk: Chunk,
+    /// The chunk being executed, or an empty one until a chunk is loaded.
+    chunk: Arc<Chunk>,
     stack: Stack,
+    /// Offset of the next instruction to execute.
+    ip: usize,
+    /// How the chunk finished executing, once it has.
+    result: Option<InterpretResult>,
+    /// Whether execution is paused on the instruction at `ip`, in which case
+    /// resuming must not immediately pause on a breakpoint there again.
+    paused: bool,
+    /// Number of instructions the VM may still execute, if limited.
+    fuel: Option<u64>,
+    policy: ArithmeticPolicy,
+    checked: bool,
+    /// Whether to print each instruction and the stack as they execute.
+    trace: bool,
+    /// Where program output and traces are written.
+    output: Box<dyn io::Write + Send>,
+    /// Where compile errors, runtime errors, and reports are written.
+    errors: Box<dyn io::Write + Send>,
+    profile: Option<Profile>,
+    coverage: Option<Coverage>,
+    hooks: Vec<Box<dyn VmHook>>,
+    /// Lines to pause on, by source file.
+    breakpoints: BTreeMap<String, BTreeSet<Line>>,
+}
+
+/// Settings for a new VM. Start from `VmConfig::new()` and chain calls for
+/// whatever should differ from the defaults, e.g.
+/// `VmConfig::new().fuel(10_000).checked_arithmetic(true)`.
+pub struct VmConfig {
+    fuel: Option<u64>,
+    policy: ArithmeticPolicy,
+    checked: bool,
+    trace: bool,
+    profiling: bool,
+    coverage: bool,
+    output: Box<dyn io::Write + Send>,
+    errors: Box<dyn io::Write + Send>,
+}
+
+impl VmConfig {
+    /// Returns the default configuration: no fuel limit, IEEE arithmetic
+    /// without checks, tracing in debug builds only, no profiling or
+    /// coverage, output to stdout, and errors to stderr.
+    pub fn new() -> VmConfig {
+        return VmConfig {
+            fuel: None,
+            policy: ArithmeticPolicy::Ieee,
+            checked: false,
+            trace: cfg!(debug_assertions),
+            profiling: false,
+            coverage: false,
+            output: Box::new(io::stdout()),
+            errors: Box::new(io::stderr()),
+        };
+    }
+
+    /// Limits the number of instructions the VM may execute; see
+    /// `VM::set_fuel`.
+    pub fn fuel(mut self, fuel: u64) -> VmConfig {
+        self.fuel = Some(fuel);
+        return self;
+    }
+
+    /// See `VM::set_arithmetic_policy`.
+    pub fn arithmetic_policy(mut self, policy: ArithmeticPolicy) -> VmConfig {
+        self.policy = policy;
+        return self;
+    }
+
+    /// See `VM::set_checked_arithmetic`.
+    pub fn checked_arithmetic(mut self, checked: bool) -> VmConfig {
+        self.checked = checked;
+        return self;
+    }
+
+    /// Prints every instruction and the stack before it as they execute.
+    pub fn trace(mut self, trace: bool) -> VmConfig {
+        self.trace = trace;
+        return self;
+    }
+
+    /// See `VM::enable_profiling`.
+    pub fn profiling(mut self, profiling: bool) -> VmConfig {
+        self.profiling = profiling;
+        return self;
+    }
+
+    /// See `VM::enable_coverage`.
+    pub fn coverage(mut self, coverage: bool) -> VmConfig {
+        self.coverage = coverage;
+        return self;
+    }
+
+    /// Sends the values printed by the program, and traces, to `output`
+    /// instead of stdout.
+    pub fn output(mut self, output: Box<dyn io::Write + Send>) -> VmConfig {
+        self.output = output;
+        return self;
+    }
+
+    /// Sends error messages, and the profile printed by `VM::close`, to
+    /// `errors` instead of stderr. Failing to write them is ignored.
+    pub fn errors(mut self, errors: Box<dyn io::Write + Send>) -> VmConfig {
+        self.errors = errors;
+        return self;
+    }
 }
 
 impl VM {
-    pub fn new(chunk: Chunk) -> VM {
-        return VM {
-            chunk: chunk,
+    pub fn new(config: VmConfig) -> VM {
+        let mut vm = VM {
+            chunk: Arc::new(Chunk::new()),
             stack: Stack::new(),
+            ip: 0,
+            result: None,
+            paused: false,
+            fuel: config.fuel,
+            policy: config.policy,
+            checked: config.checked,
+            trace: config.trace,
+            output: config.output,
+            errors: config.errors,
+            profile: None,
+            coverage: None,
+            hooks: Vec::new(),
+            breakpoints: BTreeMap::new(),
+        };
+        if config.profiling {
+            vm.enable_profiling();
+        }
+        if config.coverage {
+            vm.enable_coverage();
+        }
+        return vm;
+    }
+
+    /// Runs `chunk` from the beginning to completion. The VM keeps its
+    /// configuration, hooks, and breakpoints from one chunk to the next.
+    /// Chunks that fail `Chunk::verify` are reported to the error output
+    /// and not run.
+    pub fn interpret(&mut self, chunk: impl Into<Arc<Chunk>>) -> InterpretResult {
+        if let Err(err) = self.load(chunk) {
+            let _ = writeln!(self.errors, "{}", err);
+            return InterpretResult::CompileError;
+        }
+        self.run(Until::End);
+        return self.result.unwrap_or(InterpretResult::RuntimeError);
+    }
+
+    /// Replaces the chunk being executed with `chunk` and pauses before its
+    /// first instruction, ready for `step`, `step_over`, or `continue_`.
+    /// The stack is cleared, and coverage, if enabled, starts over for the
+    /// new chunk.
+    ///
+    /// Fails if `chunk` doesn't pass `Chunk::verify`, in which case an empty
+    /// chunk is loaded instead and resuming reports
+    /// `InterpretResult::CompileError`.
+    pub fn load(&mut self, chunk: impl Into<Arc<Chunk>>) -> Result<(), VerifyError> {
+        let chunk = chunk.into();
+        let verified = chunk.verify();
+        self.chunk = match verified {
+            Ok(()) => chunk,
+            Err(_) => Arc::new(Chunk::new()),
+        };
+        self.ip = 0;
+        self.result = verified
+            .as_ref()
+            .err()
+            .map(|_| InterpretResult::CompileError);
+        self.paused = false;
+        self.stack.reset();
+        if self.coverage.is_some() {
+            self.coverage = Some(Coverage::new(&self.chunk));
+        }
+        return verified;
+    }
+
+    /// Executes a single instruction and pauses again.
+    pub fn step(&mut self) -> StepOutcome {
+        return self.run(Until::Instructions(1));
+    }
+
+    /// Executes up to `max_instructions` instructions, stopping early if the
+    /// chunk finishes. Breakpoints are ignored. Hosts can call this
+    /// repeatedly from their own event loop to interleave scripts with
+    /// other work; all state is kept between calls.
+    pub fn run_for(&mut self, max_instructions: u64) -> StepOutcome {
+        return self.run(Until::Instructions(max_instructions));
+    }
+
+    /// Executes instructions until execution reaches a different source
+    /// line than the one it is paused on.
+    pub fn step_over(&mut self) -> StepOutcome {
+        return match self.location() {
+            Some((_, line)) => self.run(Until::LineChange(line)),
+            None => self.run(Until::End),
+        };
+    }
+
+    /// Executes instructions until a breakpoint is hit or the chunk
+    /// finishes.
+    pub fn continue_(&mut self) -> StepOutcome {
+        return self.run(Until::Breakpoint);
+    }
+
+    /// Makes `continue_` pause before running the first instruction of
+    /// `line` in `file` whenever execution enters that line, including when
+    /// a freshly loaded chunk starts on it. Resuming from a breakpoint runs
+    /// the line without pausing on it again. `step` and `run_for` always
+    /// execute the instructions they were asked for, `step_over` pauses on
+    /// every new line anyway, and `interpret` ignores breakpoints.
+    pub fn set_breakpoint(&mut self, file: &str, line: Line) {
+        self.breakpoints
+            .entry(file.to_string())
+            .or_default()
+            .insert(line);
+    }
+
+    pub fn clear_breakpoint(&mut self, file: &str, line: Line) {
+        if let Some(lines) = self.breakpoints.get_mut(file) {
+            lines.remove(&line);
+        }
+    }
+
+    /// Returns the offset and line of the instruction execution is paused
+    /// on, or `None` once the chunk has finished.
+    pub fn location(&self) -> Option<(usize, Line)> {
+        if self.result.is_some() || self.ip >= self.chunk.instructions.len() {
+            return None;
+        }
+        return Some((self.ip, self.chunk.line_at(self.ip)));
+    }
+
+    /// Returns the values currently on the stack, bottom first.
+    pub fn stack_values(&self) -> &[Value] {
+        return self.stack.values();
+    }
+
+    /// Returns the chunk being executed, e.g. to disassemble it around
+    /// `location`.
+    pub fn chunk(&self) -> &Chunk {
+        return &self.chunk;
+    }
+
+    /// Serializes the execution state of this VM (its chunk, stack,
+    /// instruction pointer, and remaining fuel) so that `restore` can resume
+    /// it later, e.g. to checkpoint a long-running script paused with `step`
+    /// or `run_for`. Running out of fuel finishes the chunk, so a snapshot
+    /// taken then restores a VM that stays finished. Hooks, breakpoints,
+    /// profiles, and coverage belong to the host and are not included.
+    pub fn snapshot(&self) -> Vec<u8> {
+        let mut bytes = Vec::new();
+        bytes.extend_from_slice(SNAPSHOT_MAGIC);
+        self.chunk.write_to(&mut bytes, true);
+        bytes.extend_from_slice(&(self.stack.cursor as u32).to_le_bytes());
+        for value in self.stack.values() {
+            bytes.extend_from_slice(&value.to_le_bytes());
+        }
+        bytes.extend_from_slice(&(self.ip as u64).to_le_bytes());
+        bytes.push(match self.result {
+            None => 0,
+            Some(InterpretResult::Ok) => 1,
+            Some(InterpretResult::CompileError) => 2,
+            Some(InterpretResult::RuntimeError) => 3,
+            Some(InterpretResult::FuelExhausted) => 4,
+        });
+        bytes.push(self.paused as u8);
+        match self.fuel {
+            Some(fuel) => {
+                bytes.push(1);
+                bytes.extend_from_slice(&fuel.to_le_bytes());
+            }
+            None => bytes.push(0),
+        }
+        return bytes;
+    }
+
+    /// Recreates a VM from a `snapshot`, configured by `config` except for
+    /// its fuel, which is restored from the snapshot.
+    pub fn restore(bytes: &[u8], config: VmConfig) -> Result<VM, LoadError> {
+        let mut reader = ByteReader::new(bytes);
+        reader.expect(SNAPSHOT_MAGIC)?;
+        let mut vm = VM::new(config);
+        vm.load(Chunk::read_from(&mut reader)?)
+            .map_err(LoadError::Invalid)?;
+        let depth = reader.u32()? as usize;
+        if depth > STACK_MAX {
+            return Err(LoadError::State);
+        }
+        for _ in 0..depth {
+            vm.stack.push(reader.f64()?);
+        }
+        vm.ip = usize::try_from(reader.u64()?).map_err(|_| LoadError::State)?;
+        // Resuming anywhere but at the start of an instruction (or the very
+        // end of the chunk) would decode operands as op codes.
+        let at_boundary = vm.ip == vm.chunk.instructions.len()
+            || vm.chunk.iter().any(|(offset, _, _)| offset == vm.ip);
+        if !at_boundary {
+            return Err(LoadError::State);
+        }
+        vm.result = match reader.u8()? {
+            0 => None,
+            1 => Some(InterpretResult::Ok),
+            2 => Some(InterpretResult::CompileError),
+            3 => Some(InterpretResult::RuntimeError),
+            4 => Some(InterpretResult::FuelExhausted),
+            _ => return Err(LoadError::State),
+        };
+        // Unless the chunk has already finished, the code at `ip` must find
+        // exactly the stack it expects or it could underflow.
+        if vm.result.is_none() && vm.chunk.depth_at(vm.ip) != Some(depth) {
+            return Err(LoadError::State);
+        }
+        vm.paused = reader.flag()?;
+        vm.fuel = if reader.flag()? {
+            Some(reader.u64()?)
+        } else {
+            None
+        };
+        reader.finish()?;
+        return Ok(vm);
+    }
+
+    /// Executes instructions starting at the instruction pointer until
+    /// `until` is satisfied or the chunk finishes, leaving the VM ready to
+    /// resume where it stopped.
+    fn run(&mut self, until: Until) -> StepOutcome {
+        if let Some(result) = self.result {
+            return StepOutcome::from(result);
+        }
+        let breakpoints = match (self.chunk.file(), until) {
+            (Some(file), Until::Breakpoint) => self.breakpoints.get(file),
+            _ => None,
+        };
+        let resuming = self.paused;
+        self.paused = false;
+        let mut executed: u64 = 0;
+        let mut previous_line = None;
+        let mut ctx = Execution {
+            chunk: &self.chunk,
+            stack: &mut self.stack,
+            output: &mut *self.output,
+            errors: &mut *self.errors,
+            policy: self.policy,
+            checked: self.checked,
+            trace: self.trace,
+            line: 0,
+            output_failed: false,
+            ip: self.ip,
+        };
+        let result = loop {
+            if ctx.finished() {
+                break InterpretResult::RuntimeError;
+            }
+            let offset = ctx.offset();
+            let line = self.chunk.line_at(offset);
+            let entered_line = match previous_line {
+                Some(previous) => previous != line,
+                None => !resuming,
+            };
+            let pause = match until {
+                Until::End | Until::Breakpoint => false,
+                Until::Instructions(n) => executed >= n,
+                Until::LineChange(from) => line != from,
+            } || (entered_line
+                && breakpoints.is_some_and(|lines| lines.contains(&line)));
+            if pause {
+                self.ip = offset;
+                self.paused = true;
+                return StepOutcome::Paused;
+            }
+            if let Some(remaining) = &mut self.fuel {
+                if *remaining == 0 {
+                    break InterpretResult::FuelExhausted;
+                }
+                *remaining -= 1;
+            }
+            if ctx.trace {
+                let stack = ctx.stack.debug();
+                ctx.write_output(format_args!("{}\n", stack));
+            }
+            let op = ctx.opcode();
+            if let Some(coverage) = &mut self.coverage {
+                coverage.record(offset, line);
+            }
+            // Only pay for decoding the instruction twice when someone is
+            // listening.
+            let inst = if self.hooks.is_empty() {
+                None
+            } else {
+                Some(DecodedInstruction::marshal(&self.chunk, offset).1)
+            };
+            if let Some(inst) = &inst {
+                for hook in &mut self.hooks {
+                    hook.before_instruction(offset, line, inst, ctx.stack.values());
+                }
+            }
+            let handler = HANDLERS[op as usize];
+            let flow = match &mut self.profile {
+                Some(profile) => {
+                    let start = Instant::now();
+                    let flow = handler(&mut ctx);
+                    profile.record(op, line, start.elapsed());
+                    flow
+                }
+                None => handler(&mut ctx),
+            };
+            if let Some(inst) = &inst {
+                for hook in &mut self.hooks {
+                    hook.after_instruction(offset, line, inst, ctx.stack.values());
+                }
+            }
+            executed += 1;
+            previous_line = Some(line);
+            let flow = if ctx.output_failed {
+                ctx.runtime_error("Could not write output.")
+            } else {
+                flow
+            };
+            if let Flow::Halt(result) = flow {
+                break result;
+            }
+        };
+        self.ip = ctx.offset();
+        self.result = Some(result);
+        return StepOutcome::from(result);
+    }
+
+    /// Installs a hook that observes every instruction the VM executes from
+    /// now on. Hooks are called in the order they were added.
+    pub fn add_hook(&mut self, hook: Box<dyn VmHook>) {
+        self.hooks.push(hook);
+    }
+
+    /// Limits the number of instructions the VM may execute from now on, or
+    /// lifts the limit when `None`. Once the budget is used up, interpretation
+    /// stops with `InterpretResult::FuelExhausted`.
+    pub fn set_fuel(&mut self, fuel: Option<u64>) {
+        self.fuel = fuel;
+    }
+
+    /// Chooses how arithmetic that divides by zero or produces NaN is
+    /// treated. Defaults to `ArithmeticPolicy::Ieee`.
+    pub fn set_arithmetic_policy(&mut self, policy: ArithmeticPolicy) {
+        self.policy = policy;
+    }
+
+    /// Turns checked arithmetic on or off. When on, arithmetic that
+    /// overflows to infinity, or yields a result whose magnitude is above
+    /// 2^53 (past which not every integer can be represented), is a runtime
+    /// error reported with its source line. This is a range check: it
+    /// doesn't catch rounding of fractions, e.g. in `0.1 + 0.2`.
+    pub fn set_checked_arithmetic(&mut self, checked: bool) {
+        self.checked = checked;
+    }
+
+    /// Returns how many more instructions the VM may execute, if limited.
+    pub fn fuel(&self) -> Option<u64> {
+        return self.fuel;
+    }
+
+    /// Starts counting executions and time spent per op code. The collected
+    /// profile is available through `profile` and is reported when the VM is
+    /// closed.
+    pub fn enable_profiling(&mut self) {
+        if self.profile.is_none() {
+            self.profile = Some(Profile::new());
+        }
+    }
+
+    pub fn profile(&self) -> Option<&Profile> {
+        return self.profile.as_ref();
+    }
+
+    /// Starts recording which instructions, and hence which source lines,
+    /// are executed. The result is available through `coverage`.
+    pub fn enable_coverage(&mut self) {
+        if self.coverage.is_none() {
+            self.coverage = Some(Coverage::new(&self.chunk));
+        }
+    }
+
+    pub fn coverage(&self) -> Option<&Coverage> {
+        return self.coverage.as_ref();
+    }
+
+    /// Writes the profile, if profiling is enabled, to the error output.
+    pub fn close(&mut self) {
+        if let Some(profile) = &self.profile {
+            let _ = write!(self.errors, "{}", profile.report());
+        }
+    }
+}
+
+/// Which instructions and source lines of a chunk have been executed.
+pub struct Coverage {
+    /// Source file of the chunk, if known.
+    file: Option<String>,
+    offsets: Vec<bool>,
+    lines: BTreeMap<Line, bool>,
+}
+
+impl Coverage {
+    fn new(chunk: &Chunk) -> Coverage {
+        return Coverage {
+            file: chunk.file().map(str::to_string),
+            offsets: vec![false; chunk.instructions.len()],
+            lines: chunk.iter().map(|(_, line, _)| (line, false)).collect(),
         };
     }
 
-    pub fn interpret(&mut self) -> InterpretResult {
-        return self.chunk.interpret(&mut self.stack);
+    fn record(&mut self, offset: usize, line: Line) {
+        self.offsets[offset] = true;
+        self.lines.insert(line, true);
+    }
+
+    /// Returns whether the instruction at `offset` has been executed.
+    pub fn executed(&self, offset: usize) -> bool {
+        return self.offsets.get(offset).copied().unwrap_or(false);
+    }
+
+    /// Returns the lines with instructions that have never been executed.
+    pub fn unexecuted_lines(&self) -> Vec<Line> {
+        return self
+            .lines
+            .iter()
+            .filter(|(_, &executed)| !executed)
+            .map(|(&line, _)| line)
+            .collect();
+    }
+
+    /// Renders an annotated listing of every line with instructions, marking
+    /// the ones that were never executed, followed by a summary. The header
+    /// names the chunk's source file when it has one.
+    pub fn report(&self) -> String {
+        let mut report = match &self.file {
+            Some(file) => format!("=== coverage: {} ===\n", file),
+            None => String::from("=== coverage ===\n"),
+        };
+        for (line, executed) in &self.lines {
+            let mark = if *executed { "" } else { "#####" };
+            writeln!(&mut report, "{:>5} {:0>4}", mark, line).unwrap();
+        }
+        let covered = self.lines.values().filter(|&&executed| executed).count();
+        writeln!(
+            &mut report,
+            "{}/{} lines executed",
+            covered,
+            self.lines.len()
+        )
+        .unwrap();
+        return report;
+    }
+}
+
+/// Execution counts and accumulated handler time per op code, plus the time
+/// attributed to each source line.
+pub struct Profile {
+    counts: [u64; 256],
+    time: [Duration; 256],
+    lines: BTreeMap<Line, Duration>,
+}
+
+impl Profile {
+    fn new() -> Profile {
+        return Profile {
+            counts: [0; 256],
+            time: [Duration::ZERO; 256],
+            lines: BTreeMap::new(),
+        };
+    }
+
+    fn record(&mut self, op: u8, line: Line, elapsed: Duration) {
+        self.counts[op as usize] += 1;
+        self.time[op as usize] += elapsed;
+        *self.lines.entry(line).or_insert(Duration::ZERO) += elapsed;
+    }
+
+    /// Returns the accumulated time spent executing instructions from each
+    /// source line, in line order.
+    pub fn lines(&self) -> Vec<(Line, Duration)> {
+        return self
+            .lines
+            .iter()
+            .map(|(&line, &time)| (line, time))
+            .collect();
+    }
+
+    /// Renders the per-line times in the collapsed stack format consumed by
+    /// flamegraph tooling (`frame;frame weight`), weighted in nanoseconds.
+    pub fn collapsed(&self) -> String {
+        let mut collapsed = String::new();
+        for (line, time) in self.lines() {
+            writeln!(&mut collapsed, "script;line {} {}", line, time.as_nanos()).unwrap();
+        }
+        return collapsed;
+    }
+
+    /// Returns the name, execution count, and accumulated time of every op
+    /// code that was executed, most expensive first.
+    pub fn entries(&self) -> Vec<(&'static str, u64, Duration)> {
+        let mut entries: Vec<(&'static str, u64, Duration)> = (0..=u8::MAX)
+            .filter(|&op| self.counts[op as usize] > 0)
+            .map(|op| {
+                (
+                    op_name(op),
+                    self.counts[op as usize],
+                    self.time[op as usize],
+                )
+            })
+            .collect();
+        entries.sort_by_key(|&(_, _, time)| std::cmp::Reverse(time));
+        return entries;
     }
 
-    pub fn close(&self) {}
+    /// Renders the profile as a table.
+    pub fn report(&self) -> String {
+        let mut report = String::from("=== profile ===\n");
+        writeln!(
+            &mut report,
+            "{:<18} {:>10} {:>12}",
+            "op code", "count", "time (ns)"
+        )
+        .unwrap();
+        for (name, count, time) in self.entries() {
+            writeln!(
+                &mut report,
+                "{:<18} {:>10} {:>12}",
+                name,
+                count,
+                time.as_nanos()
+            )
+            .unwrap();
+        }
+        return report;
+    }
 }
 
+/// Observes a running VM, e.g. to build tracers, debuggers, or profilers
+/// without modifying the interpret loop. All methods do nothing by default.
+///
+/// Hooks must be `Send` so that the VM owning them stays `Send`.
+pub trait VmHook: Send {
+    /// Called before the instruction at `offset` executes, with the stack as
+    /// it is at that point (bottom first).
+    fn before_instruction(
+        &mut self,
+        _offset: usize,
+        _line: Line,
+        _inst: &DecodedInstruction,
+        _stack: &[Value],
+    ) {
+    }
+
+    /// Called after the instruction at `offset` has executed, with the stack
+    /// as it left it.
+    fn after_instruction(
+        &mut self,
+        _offset: usize,
+        _line: Line,
+        _inst: &DecodedInstruction,
+        _stack: &[Value],
+    ) {
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
 pub enum InterpretResult {
     Ok,
     CompileError,
     RuntimeError,
+    /// The instruction budget set with `VM::set_fuel` ran out.
+    FuelExhausted,
+}
+
+// Keep VM movable across threads; see the VM docs.
+const _: fn() = || {
+    fn assert_send<T: Send>() {}
+    assert_send::<VM>();
+};
+
+/// Largest integer magnitude up to which every integer is exactly
+/// representable as a `Value`.
+pub(crate) const MAX_EXACT_INTEGER: f64 = 9007199254740992.0;
+
+/// How strictly the VM treats arithmetic edge cases.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum ArithmeticPolicy {
+    /// Follow IEEE 754: `1 / 0` is infinity and `0 / 0` is NaN.
+    Ieee,
+    /// Dividing by zero is a runtime error.
+    ErrorOnDivisionByZero,
+    /// Any arithmetic producing NaN is a runtime error.
+    ErrorOnNaN,
+}
+
+/// Where a VM stopped after being resumed with `step`, `step_over`, or
+/// `continue_`.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum StepOutcome {
+    /// Execution paused before the instruction reported by `VM::location`.
+    Paused,
+    /// The chunk finished successfully.
+    Done,
+    /// The chunk stopped with an error or ran out of fuel.
+    Error(InterpretResult),
+}
+
+impl From<InterpretResult> for StepOutcome {
+    fn from(result: InterpretResult) -> StepOutcome {
+        return match result {
+            InterpretResult::Ok => StepOutcome::Done,
+            _ => StepOutcome::Error(result),
+        };
+    }
+}
+
+/// When `VM::run` should pause.
+#[derive(Clone, Copy)]
+enum Until {
+    /// Never; run until the chunk finishes.
+    End,
+    /// Only on breakpoints.
+    Breakpoint,
+    /// After executing this many instructions.
+    Instructions(u64),
+    /// Before the first instruction belonging to a line other than this one.
+    LineChange(Line),
 }
 
+/// Maximum number of values that fit on the VM's stack.
+pub(crate) const STACK_MAX: usize = 256;
+
 struct Stack {
-    values: [Value; 256],
+    values: [Value; STACK_MAX],
     cursor: usize,
 }
 
 impl Stack {
     fn new() -> Stack {
         return Stack {
-            values: [0.0; 256],
+            values: [0.0; STACK_MAX],
             cursor: 0,
         };
     }
@@ -49,402 +774,856 @@ impl Stack {
         return self.values[self.cursor];
     }
 
+    /// Returns the topmost value without popping it.
+    fn peek(&self) -> Value {
+        return self.values[self.cursor - 1];
+    }
+
+    /// Pops `count` values at once, discarding them.
+    fn discard(&mut self, count: usize) {
+        self.cursor -= count;
+    }
+
+    fn reset(&mut self) {
+        self.cursor = 0;
+    }
+
+    /// Returns the values currently on the stack, bottom first.
+    fn values(&self) -> &[Value] {
+        return &self.values[..self.cursor];
+    }
+
     fn debug(&self) -> String {
         let mut debug = String::new();
         for idx in 0..self.cursor {
-            write!(&mut debug, "{} ", self.values[idx]).unwrap();
+            write!(&mut debug, "{} ", ValueDisplay(self.values[idx])).unwrap();
         }
-        if debug.len() > 0 {
+        if !debug.is_empty() {
             debug.pop();
         }
         format!("[{}]", debug)
     }
 }
 
-pub struct Chunk {
-    instructions: Vec<u8>,
-    lines: Vec<Line>,
-    pool: Pool,
+const SNAPSHOT_MAGIC: &[u8] = b"RLOXS\x02";
+
+/// State shared by the instruction handlers while a chunk is interpreted.
+struct Execution<'a> {
+    chunk: &'a Chunk,
+    stack: &'a mut Stack,
+    output: &'a mut dyn io::Write,
+    errors: &'a mut dyn io::Write,
+    policy: ArithmeticPolicy,
+    checked: bool,
+    trace: bool,
+    /// Line of the instruction being executed, for error reporting.
+    line: Line,
+    /// Whether writing to `output` has failed during this instruction.
+    output_failed: bool,
+    /// Offset of the next instruction to execute.
+    ip: usize,
 }
 
-impl Chunk {
-    pub fn new() -> Chunk {
-        return Chunk {
-            instructions: Vec::new(),
-            lines: Vec::new(),
-            pool: Pool::new(),
-        };
+impl<'a> Execution<'a> {
+    /// Returns the offset of the next instruction to execute.
+    fn offset(&self) -> usize {
+        return self.ip;
     }
 
-    fn interpret(&self, stack: &mut Stack) -> InterpretResult {
-        let mut idx = 0;
-        while idx < self.instructions.len() {
-            let consumed: usize;
-            if cfg!(debug_assertions) {
-                println!("{}", stack.debug());
-            }
-            match self.instructions[idx] {
-                CONSTANT => {
-                    let inst;
-                    (consumed, inst) = Constant::marshal(self, idx);
-                    if cfg!(debug_assertions) {
-                        println!("{:0>4} {:0>4} {}", idx, self.lines[idx], inst.disassemble());
-                    }
-                    stack.push(inst.value);
-                }
-                ADD => {
-                    let inst;
-                    (consumed, inst) = Add::marshal(self, idx);
-                    if cfg!(debug_assertions) {
-                        println!("{:0>4} {:0>4} {}", idx, self.lines[idx], inst.disassemble());
-                    }
-                    let b = stack.pop();
-                    let a = stack.pop();
-                    stack.push(a + b);
-                }
-                SUBTRACT => {
-                    let inst;
-                    (consumed, inst) = Subtract::marshal(self, idx);
-                    if cfg!(debug_assertions) {
-                        println!("{:0>4} {:0>4} {}", idx, self.lines[idx], inst.disassemble());
-                    }
-                    let b = stack.pop();
-                    let a = stack.pop();
-                    stack.push(a - b);
-                }
-                MULTIPLY => {
-                    let inst;
-                    (consumed, inst) = Multiply::marshal(self, idx);
-                    if cfg!(debug_assertions) {
-                        println!("{:0>4} {:0>4} {}", idx, self.lines[idx], inst.disassemble());
-                    }
-                    let b = stack.pop();
-                    let a = stack.pop();
-                    stack.push(a * b);
-                }
-                DIVIDE => {
-                    let inst;
-                    (consumed, inst) = Divide::marshal(self, idx);
-                    if cfg!(debug_assertions) {
-                        println!("{:0>4} {:0>4} {}", idx, self.lines[idx], inst.disassemble());
-                    }
-                    let b = stack.pop();
-                    let a = stack.pop();
-                    stack.push(a / b);
-                }
-                NEGATE => {
-                    let inst;
-                    (consumed, inst) = Negate::marshal(self, idx);
-                    if cfg!(debug_assertions) {
-                        println!("{:0>4} {:0>4} {}", idx, self.lines[idx], inst.disassemble());
-                    }
-                    let value = -stack.pop();
-                    stack.push(value);
-                }
-                RETURN => {
-                    let inst;
-                    (_, inst) = Return::marshal(self, idx);
-                    if cfg!(debug_assertions) {
-                        println!("{:0>4} {:0>4} {}", idx, self.lines[idx], inst.disassemble());
-                    }
-                    println!("{}", stack.pop());
-                    return InterpretResult::Ok;
-                }
-                _ => return InterpretResult::RuntimeError,
-            }
-            idx += consumed;
-        }
-        return InterpretResult::RuntimeError;
+    fn finished(&self) -> bool {
+        return self.ip >= self.chunk.instructions.len();
     }
 
-    fn disassemble(&self, name: &str) {
-        println!("=== {name} chunk ===");
-        let mut idx = 0;
-        while idx < self.instructions.len() {
-            let (consumed, disassembled) = match self.instructions[idx] {
-                CONSTANT => {
-                    let (c, inst) = Constant::marshal(&self, idx);
-                    (c, inst.disassemble())
-                }
-                ADD => {
-                    let (c, inst) = Add::marshal(&self, idx);
-                    (c, inst.disassemble())
-                }
-                SUBTRACT => {
-                    let (c, inst) = Subtract::marshal(&self, idx);
-                    (c, inst.disassemble())
-                }
-                MULTIPLY => {
-                    let (c, inst) = Multiply::marshal(&self, idx);
-                    (c, inst.disassemble())
-                }
-                DIVIDE => {
-                    let (c, inst) = Divide::marshal(&self, idx);
-                    (c, inst.disassemble())
-                }
-                NEGATE => {
-                    let (c, inst) = Negate::marshal(&self, idx);
-                    (c, inst.disassemble())
-                }
-                RETURN => {
-                    let (c, inst) = Return::marshal(&self, idx);
-                    (c, inst.disassemble())
-                }
-                _ => panic!("unknown op code"),
-            };
-            println!("{:0>4} {:0>4} {}", idx, self.lines[idx], disassembled);
-            idx += consumed;
-        }
+    fn opcode(&self) -> u8 {
+        return self.chunk.instructions[self.ip];
+    }
+
+    fn advance(&mut self, consumed: usize) {
+        self.ip += consumed;
     }
 }
 
-type Value = f64;
+impl Execution<'_> {
+    /// Marshals the instruction at the instruction pointer and advances past
+    /// it, tracing it if the VM was configured to.
+    fn decode<I: Instruction>(&mut self) -> I {
+        let offset = self.offset();
+        let (consumed, inst) = I::marshal(self.chunk, offset);
+        self.line = self.chunk.line_at(offset);
+        if self.trace {
+            self.write_output(format_args!(
+                "{:0>4} {:0>4} {}\n",
+                offset,
+                self.chunk.line_at(offset),
+                inst.disassemble()
+            ));
+        }
+        self.advance(consumed);
+        return inst;
+    }
 
-struct Pool {
-    constants: Vec<Value>,
-}
+    /// Writes program output or a trace line. A failed write doesn't stop
+    /// the instruction, but the run loop halts with a runtime error after it.
+    fn write_output(&mut self, args: std::fmt::Arguments) {
+        if self.output.write_fmt(args).is_err() {
+            self.output_failed = true;
+        }
+    }
 
-impl Pool {
-    fn new() -> Pool {
-        return Pool {
-            constants: Vec::new(),
+    /// Reports a runtime error at the current instruction, along with its
+    /// source file if the chunk knows it, and halts.
+    fn runtime_error(&mut self, message: &str) -> Flow {
+        // The error is reported through the result, so there is nothing
+        // more to do if the message can't be written.
+        let _ = match self.chunk.file() {
+            Some(file) => writeln!(
+                self.errors,
+                "{}\n[{}:{}] in script",
+                message, file, self.line
+            ),
+            None => writeln!(self.errors, "{}\n[line {}] in script", message, self.line),
         };
+        return Flow::Halt(InterpretResult::RuntimeError);
     }
 
-    fn add(&mut self, constant: Value) -> usize {
-        self.constants.push(constant);
-        return self.constants.len() - 1;
+    /// Pushes the result of an arithmetic instruction, unless the VM's
+    /// arithmetic policy or checked arithmetic rejects it.
+    fn push_number(&mut self, value: Value) -> Flow {
+        if value.is_nan() && self.policy == ArithmeticPolicy::ErrorOnNaN {
+            return self.runtime_error("Arithmetic produced NaN.");
+        }
+        if self.checked {
+            if value.is_infinite() {
+                return self.runtime_error("Arithmetic overflow.");
+            }
+            if value.abs() > MAX_EXACT_INTEGER {
+                return self.runtime_error("Arithmetic result magnitude exceeds 2^53.");
+            }
+        }
+        self.stack.push(value);
+        return Flow::Continue;
     }
 
-    fn get(&self, location: usize) -> f64 {
-        return self.constants[location];
+    /// Checks the divisor of a division against the VM's arithmetic policy.
+    fn check_divisor(&mut self, divisor: Value) -> Option<Flow> {
+        if divisor == 0.0 && self.policy == ArithmeticPolicy::ErrorOnDivisionByZero {
+            return Some(self.runtime_error("Division by zero."));
+        }
+        return None;
     }
 }
 
-pub type Line = u16;
+/// What the interpret loop should do once a handler has run.
+enum Flow {
+    Continue,
+    Halt(InterpretResult),
+}
 
-const RETURN: u8 = 1;
-const CONSTANT: u8 = 2;
-const NEGATE: u8 = 3;
-const ADD: u8 = 4;
-const SUBTRACT: u8 = 5;
-const MULTIPLY: u8 = 6;
-const DIVIDE: u8 = 7;
+/// Executes the instruction at the instruction pointer of an `Execution`.
+type Handler = fn(&mut Execution) -> Flow;
+
+/// Handlers indexed by op code. Adding an op code means writing its handler
+/// and registering it in `handlers`.
+const HANDLERS: [Handler; 256] = handlers();
+
+const fn handlers() -> [Handler; 256] {
+    let mut table: [Handler; 256] = [op_unknown; 256];
+    table[RETURN as usize] = op_return;
+    table[CONSTANT as usize] = op_constant;
+    table[CONSTANT_LONG as usize] = op_constant_long;
+    table[NEGATE as usize] = op_negate;
+    table[ADD as usize] = op_add;
+    table[SUBTRACT as usize] = op_subtract;
+    table[MULTIPLY as usize] = op_multiply;
+    table[DIVIDE as usize] = op_divide;
+    table[ADD_CONSTANT as usize] = op_add_constant;
+    table[SUBTRACT_CONSTANT as usize] = op_subtract_constant;
+    table[MULTIPLY_CONSTANT as usize] = op_multiply_constant;
+    table[DIVIDE_CONSTANT as usize] = op_divide_constant;
+    table[DUP as usize] = op_dup;
+    table[POPN as usize] = op_popn;
+    table[ZERO as usize] = op_zero;
+    table[ONE as usize] = op_one;
+    table[ADD_ONE as usize] = op_add_one;
+    return table;
+}
 
-pub trait Instruction {
-    /// Marshals a new instance of this instruction from a `Chunk` starting at a
-    /// byte offset and returns the number of bytes consumed and the new
-    /// instance.
-    ///
-    /// The offset must point to the op code byte.
-    fn marshal(chunk: &Chunk, offset: usize) -> (usize, Self)
-    where
-        Self: Sized;
+fn op_unknown(_ctx: &mut Execution) -> Flow {
+    return Flow::Halt(InterpretResult::RuntimeError);
+}
 
-    /// Unmarshals this instruction into bytes in a `Chunk` with an associated
-    /// line number.
-    fn unmarshal(&self, chunk: &mut Chunk, line: Line);
+fn op_return(ctx: &mut Execution) -> Flow {
+    ctx.decode::<Return>();
+    let value = ctx.stack.pop();
+    ctx.write_output(format_args!("{}\n", ValueDisplay(value)));
+    return Flow::Halt(InterpretResult::Ok);
+}
 
-    /// Returns a debug string that describes this instruction and an assocaited
-    /// line number.
-    fn disassemble(&self) -> String;
+fn op_constant(ctx: &mut Execution) -> Flow {
+    let inst = ctx.decode::<Constant>();
+    ctx.stack.push(inst.value());
+    return Flow::Continue;
 }
 
-pub struct Return {}
+fn op_constant_long(ctx: &mut Execution) -> Flow {
+    let inst = ctx.decode::<ConstantLong>();
+    ctx.stack.push(inst.value());
+    return Flow::Continue;
+}
 
-impl Return {
-    pub fn new() -> Return {
-        return Return {};
-    }
+fn op_zero(ctx: &mut Execution) -> Flow {
+    ctx.decode::<Zero>();
+    ctx.stack.push(0.0);
+    return Flow::Continue;
+}
 
-    pub fn write(&self, chunk: &mut Chunk, line: Line) {
-        self.unmarshal(chunk, line);
-    }
+fn op_one(ctx: &mut Execution) -> Flow {
+    ctx.decode::<One>();
+    ctx.stack.push(1.0);
+    return Flow::Continue;
 }
 
-impl Instruction for Return {
-    fn marshal(_chunk: &Chunk, _offset: usize) -> (usize, Self) {
-        return (1, Return {});
-    }
+fn op_dup(ctx: &mut Execution) -> Flow {
+    ctx.decode::<Dup>();
+    let value = ctx.stack.peek();
+    ctx.stack.push(value);
+    return Flow::Continue;
+}
 
-    fn unmarshal(&self, chunk: &mut Chunk, line: Line) {
-        chunk.instructions.push(RETURN);
-        chunk.lines.push(line);
-    }
+fn op_popn(ctx: &mut Execution) -> Flow {
+    let inst = ctx.decode::<PopN>();
+    ctx.stack.discard(inst.count() as usize);
+    return Flow::Continue;
+}
 
-    fn disassemble(&self) -> String {
-        "RETURN".to_string()
-    }
+fn op_negate(ctx: &mut Execution) -> Flow {
+    ctx.decode::<Negate>();
+    let value = -ctx.stack.pop();
+    return ctx.push_number(value);
 }
 
-pub struct Negate {}
+fn op_add(ctx: &mut Execution) -> Flow {
+    ctx.decode::<Add>();
+    let b = ctx.stack.pop();
+    let a = ctx.stack.pop();
+    return ctx.push_number(a + b);
+}
 
-impl Negate {
-    pub fn new() -> Negate {
-        return Negate {};
-    }
+fn op_subtract(ctx: &mut Execution) -> Flow {
+    ctx.decode::<Subtract>();
+    let b = ctx.stack.pop();
+    let a = ctx.stack.pop();
+    return ctx.push_number(a - b);
+}
 
-    pub fn write(&self, chunk: &mut Chunk, line: Line) {
-        self.unmarshal(chunk, line);
-    }
+fn op_multiply(ctx: &mut Execution) -> Flow {
+    ctx.decode::<Multiply>();
+    let b = ctx.stack.pop();
+    let a = ctx.stack.pop();
+    return ctx.push_number(a * b);
 }
 
-impl Instruction for Negate {
-    fn marshal(_chunk: &Chunk, _offset: usize) -> (usize, Self) {
-        return (1, Negate {});
+fn op_divide(ctx: &mut Execution) -> Flow {
+    ctx.decode::<Divide>();
+    let b = ctx.stack.pop();
+    let a = ctx.stack.pop();
+    if let Some(error) = ctx.check_divisor(b) {
+        return error;
     }
+    return ctx.push_number(a / b);
+}
 
-    fn unmarshal(&self, chunk: &mut Chunk, line: Line) {
-        chunk.instructions.push(NEGATE);
-        chunk.lines.push(line);
-    }
+fn op_add_constant(ctx: &mut Execution) -> Flow {
+    let inst = ctx.decode::<AddConstant>();
+    let a = ctx.stack.pop();
+    return ctx.push_number(a + inst.value());
+}
 
-    fn disassemble(&self) -> String {
-        "NEGATE".to_string()
-    }
+fn op_add_one(ctx: &mut Execution) -> Flow {
+    ctx.decode::<AddOne>();
+    let a = ctx.stack.pop();
+    return ctx.push_number(a + 1.0);
 }
 
-pub struct Add {}
+fn op_subtract_constant(ctx: &mut Execution) -> Flow {
+    let inst = ctx.decode::<SubtractConstant>();
+    let a = ctx.stack.pop();
+    return ctx.push_number(a - inst.value());
+}
 
-impl Add {
-    pub fn new() -> Add {
-        return Add {};
-    }
+fn op_multiply_constant(ctx: &mut Execution) -> Flow {
+    let inst = ctx.decode::<MultiplyConstant>();
+    let a = ctx.stack.pop();
+    return ctx.push_number(a * inst.value());
+}
 
-    pub fn write(&self, chunk: &mut Chunk, line: Line) {
-        self.unmarshal(chunk, line);
+fn op_divide_constant(ctx: &mut Execution) -> Flow {
+    let inst = ctx.decode::<DivideConstant>();
+    let a = ctx.stack.pop();
+    if let Some(error) = ctx.check_divisor(inst.value()) {
+        return error;
     }
+    return ctx.push_number(a / inst.value());
 }
 
-impl Instruction for Add {
-    fn marshal(_chunk: &Chunk, _offset: usize) -> (usize, Self) {
-        return (1, Add {});
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_util::{run, Output};
+
+    fn chunk(build: impl FnOnce(&mut Chunk)) -> Chunk {
+        let mut chunk = Chunk::new();
+        build(&mut chunk);
+        return chunk;
     }
 
-    fn unmarshal(&self, chunk: &mut Chunk, line: Line) {
-        chunk.instructions.push(ADD);
-        chunk.lines.push(line);
+    fn arithmetic() -> Chunk {
+        return chunk(|chunk| {
+            chunk.write_constant(1.2, 1);
+            chunk.write_constant(3.4, 1);
+            Add::new().write(chunk, 1);
+            chunk.write_constant(5.6, 2);
+            Divide::new().write(chunk, 2);
+            Negate::new().write(chunk, 2);
+            Dup::new().write(chunk, 3);
+            Multiply::new().write(chunk, 3);
+            AddOne::new().write(chunk, 3);
+            SubtractConstant::new(0.5).write(chunk, 3);
+            Return::new().write(chunk, 4);
+        });
     }
 
-    fn disassemble(&self) -> String {
-        "ADD".to_string()
+    #[test]
+    fn interprets_every_instruction() {
+        let cases = [
+            (arithmetic(), "1.17474\n"),
+            (
+                chunk(|chunk| {
+                    Zero::new().write(chunk, 1);
+                    One::new().write(chunk, 1);
+                    AddConstant::new(2.0).write(chunk, 1);
+                    MultiplyConstant::new(3.0).write(chunk, 1);
+                    DivideConstant::new(4.0).write(chunk, 1);
+                    Add::new().write(chunk, 1);
+                    One::new().write(chunk, 1);
+                    Subtract::new().write(chunk, 1);
+                    Return::new().write(chunk, 1);
+                }),
+                "1.25\n",
+            ),
+            (
+                chunk(|chunk| {
+                    // Fill the pool past the one-byte operand range so the
+                    // last load is a CONSTANT_LONG.
+                    for n in 0..300 {
+                        chunk.write_constant(n as Value + 2.0, 1);
+                        PopN::new(1).write(chunk, 1);
+                    }
+                    chunk.write_constant(0.25, 2);
+                    Return::new().write(chunk, 2);
+                }),
+                "0.25\n",
+            ),
+        ];
+        for (chunk, output) in cases {
+            assert!(chunk.verify().is_ok());
+            assert_eq!(
+                run(chunk, VmConfig::new()),
+                (output.to_string(), InterpretResult::Ok)
+            );
+        }
     }
-}
 
-pub struct Subtract {}
+    #[test]
+    fn reports_runtime_errors_and_fuel_exhaustion() {
+        let divide_by_zero = chunk(|chunk| {
+            One::new().write(chunk, 1);
+            Zero::new().write(chunk, 1);
+            Divide::new().write(chunk, 1);
+            Return::new().write(chunk, 1);
+        });
+        let config = VmConfig::new().arithmetic_policy(ArithmeticPolicy::ErrorOnDivisionByZero);
+        assert_eq!(
+            run(divide_by_zero, config),
+            (String::new(), InterpretResult::RuntimeError)
+        );
 
-impl Subtract {
-    pub fn new() -> Subtract {
-        return Subtract {};
+        let config = VmConfig::new().fuel(3);
+        assert_eq!(
+            run(arithmetic(), config),
+            (String::new(), InterpretResult::FuelExhausted)
+        );
+
+        let no_return = chunk(|chunk| One::new().write(chunk, 1));
+        assert_eq!(
+            run(no_return, VmConfig::new()),
+            (String::new(), InterpretResult::RuntimeError)
+        );
     }
 
-    pub fn write(&self, chunk: &mut Chunk, line: Line) {
-        self.unmarshal(chunk, line);
+    #[test]
+    fn checked_arithmetic_limits_magnitude() {
+        let binary = |a: Value, b: Value, write_op: fn(&mut Chunk)| {
+            return chunk(|chunk| {
+                chunk.write_constant(a, 1);
+                chunk.write_constant(b, 1);
+                write_op(chunk);
+                Return::new().write(chunk, 1);
+            });
+        };
+        let cases = [
+            (
+                binary(0.1, 0.2, |c| Add::new().write(c, 1)),
+                InterpretResult::Ok,
+            ),
+            (
+                binary(MAX_EXACT_INTEGER, 1.0, |c| Multiply::new().write(c, 1)),
+                InterpretResult::Ok,
+            ),
+            (
+                binary(MAX_EXACT_INTEGER, 2.0, |c| Add::new().write(c, 1)),
+                InterpretResult::RuntimeError,
+            ),
+            (
+                binary(1e300, 1.0, |c| Multiply::new().write(c, 1)),
+                InterpretResult::RuntimeError,
+            ),
+            (
+                binary(1e308, 10.0, |c| Multiply::new().write(c, 1)),
+                InterpretResult::RuntimeError,
+            ),
+        ];
+        for (chunk, result) in cases {
+            let config = VmConfig::new().checked_arithmetic(true);
+            assert_eq!(run(chunk, config).1, result);
+        }
     }
-}
 
-impl Instruction for Subtract {
-    fn marshal(_chunk: &Chunk, _offset: usize) -> (usize, Self) {
-        return (1, Subtract {});
+    #[test]
+    fn traces_each_instruction() {
+        let output = Output::default();
+        let mut vm = VM::new(VmConfig::new().trace(true).output(Box::new(output.clone())));
+        let result = vm.interpret(chunk(|chunk| {
+            chunk.write_constant(2.0, 1);
+            AddOne::new().write(chunk, 1);
+            Return::new().write(chunk, 2);
+        }));
+        assert_eq!(result, InterpretResult::Ok);
+        assert_eq!(
+            output.text(),
+            "[]\n0000 0001 CONSTANT: 2\n[2]\n0002 0001 ADD_ONE\n[3]\n0003 0002 RETURN\n3\n"
+        );
     }
 
-    fn unmarshal(&self, chunk: &mut Chunk, line: Line) {
-        chunk.instructions.push(SUBTRACT);
-        chunk.lines.push(line);
+    #[test]
+    fn steps_through_a_chunk() {
+        let mut vm = VM::new(VmConfig::new().trace(false).output(Box::new(io::sink())));
+        vm.load(arithmetic()).unwrap();
+        let mut visited = Vec::new();
+        while let Some((offset, line)) = vm.location() {
+            visited.push((offset, line, vm.stack_values().len()));
+            if vm.step() != StepOutcome::Paused {
+                break;
+            }
+        }
+        assert_eq!(
+            visited,
+            [
+                (0, 1, 0),
+                (2, 1, 1),
+                (4, 1, 2),
+                (5, 2, 1),
+                (7, 2, 2),
+                (8, 2, 1),
+                (9, 3, 1),
+                (10, 3, 2),
+                (11, 3, 1),
+                (12, 3, 1),
+                (14, 4, 1),
+            ]
+        );
+        assert_eq!(vm.location(), None);
+
+        vm.load(arithmetic()).unwrap();
+        assert_eq!(vm.run_for(4), StepOutcome::Paused);
+        assert_eq!(vm.location(), Some((7, 2)));
+        assert_eq!(vm.stack_values(), [4.6, 5.6]);
+        assert_eq!(vm.continue_(), StepOutcome::Done);
     }
 
-    fn disassemble(&self) -> String {
-        "SUBTRACT".to_string()
+    #[test]
+    fn step_over_stops_at_each_new_line() {
+        let output = Output::default();
+        let config = VmConfig::new()
+            .trace(false)
+            .output(Box::new(output.clone()));
+        let mut vm = VM::new(config);
+        vm.load(arithmetic()).unwrap();
+        let mut visited = vec![vm.location()];
+        while vm.step_over() == StepOutcome::Paused {
+            visited.push(vm.location());
+        }
+        assert_eq!(
+            visited,
+            [Some((0, 1)), Some((5, 2)), Some((9, 3)), Some((14, 4))]
+        );
+        assert_eq!(vm.location(), None);
+        assert_eq!(output.text(), "1.17474\n");
+        assert_eq!(vm.step_over(), StepOutcome::Done);
     }
-}
 
-pub struct Multiply {}
+    #[test]
+    fn rejects_chunks_that_fail_verification() {
+        let underflow = chunk(|chunk| {
+            Add::new().write(chunk, 1);
+            Return::new().write(chunk, 1);
+        });
+        let overflow = chunk(|chunk| {
+            for _ in 0..=STACK_MAX {
+                One::new().write(chunk, 1);
+            }
+            Return::new().write(chunk, 1);
+        });
+        for chunk in [underflow, overflow] {
+            assert_eq!(
+                run(chunk, VmConfig::new()),
+                (String::new(), InterpretResult::CompileError)
+            );
+        }
 
-impl Multiply {
-    pub fn new() -> Multiply {
-        return Multiply {};
+        let mut vm = VM::new(VmConfig::new().trace(false).output(Box::new(io::sink())));
+        let lone_add = chunk(|chunk| Add::new().write(chunk, 1));
+        assert!(matches!(
+            vm.load(lone_add),
+            Err(VerifyError::StackUnderflow { offset: 0 })
+        ));
+        assert_eq!(vm.location(), None);
+        assert_eq!(vm.step(), StepOutcome::Error(InterpretResult::CompileError));
     }
 
-    pub fn write(&self, chunk: &mut Chunk, line: Line) {
-        self.unmarshal(chunk, line);
-    }
-}
+    #[test]
+    fn coverage_report_names_the_file() {
+        let mut dead_code = chunk(|chunk| {
+            One::new().write(chunk, 1);
+            Return::new().write(chunk, 2);
+            Zero::new().write(chunk, 3);
+        });
+        let config = VmConfig::new().trace(false).coverage(true);
+        let mut vm = VM::new(config.output(Box::new(io::sink())));
+        vm.interpret(chunk(|chunk| {
+            One::new().write(chunk, 1);
+            Return::new().write(chunk, 2);
+        }));
+        assert_eq!(
+            vm.coverage().unwrap().report(),
+            "=== coverage ===\n      0001\n      0002\n2/2 lines executed\n"
+        );
 
-impl Instruction for Multiply {
-    fn marshal(_chunk: &Chunk, _offset: usize) -> (usize, Self) {
-        return (1, Multiply {});
+        dead_code.set_file("main.lox");
+        vm.interpret(dead_code);
+        assert_eq!(
+            vm.coverage().unwrap().report(),
+            "=== coverage: main.lox ===\n      0001\n      0002\n##### 0003\n2/3 lines executed\n"
+        );
     }
 
-    fn unmarshal(&self, chunk: &mut Chunk, line: Line) {
-        chunk.instructions.push(MULTIPLY);
-        chunk.lines.push(line);
+    #[test]
+    fn restores_snapshots() {
+        let output = Output::default();
+        let config = VmConfig::new().trace(false).fuel(100);
+        let mut vm = VM::new(config.output(Box::new(output.clone())));
+        let mut chunk = arithmetic();
+        chunk.set_file("main.lox");
+        vm.load(chunk).unwrap();
+        assert_eq!(vm.run_for(4), StepOutcome::Paused);
+
+        let restored_output = Output::default();
+        let config = VmConfig::new().trace(false);
+        let config = config.output(Box::new(restored_output.clone()));
+        let mut restored = VM::restore(&vm.snapshot(), config).unwrap();
+        assert_eq!(restored.location(), vm.location());
+        assert_eq!(restored.stack_values(), vm.stack_values());
+        assert_eq!(restored.fuel(), Some(96));
+        assert_eq!(restored.chunk().file(), Some("main.lox"));
+        assert_eq!(restored.snapshot(), vm.snapshot());
+
+        assert_eq!(restored.continue_(), StepOutcome::Done);
+        assert_eq!(vm.continue_(), StepOutcome::Done);
+        assert_eq!(restored_output.text(), output.text());
+
+        let mut exhausted = VM::new(VmConfig::new().trace(false).fuel(3));
+        assert_eq!(
+            exhausted.interpret(arithmetic()),
+            InterpretResult::FuelExhausted
+        );
+        let mut restored = VM::restore(&exhausted.snapshot(), VmConfig::new()).unwrap();
+        restored.set_fuel(None);
+        assert_eq!(
+            restored.continue_(),
+            StepOutcome::Error(InterpretResult::FuelExhausted)
+        );
     }
 
-    fn disassemble(&self) -> String {
-        "MULTIPLY".to_string()
-    }
-}
+    struct FailingOutput;
 
-pub struct Divide {}
+    impl io::Write for FailingOutput {
+        fn write(&mut self, _: &[u8]) -> io::Result<usize> {
+            return Err(io::Error::new(io::ErrorKind::BrokenPipe, "closed"));
+        }
 
-impl Divide {
-    pub fn new() -> Divide {
-        return Divide {};
+        fn flush(&mut self) -> io::Result<()> {
+            return Ok(());
+        }
     }
 
-    pub fn write(&self, chunk: &mut Chunk, line: Line) {
-        self.unmarshal(chunk, line);
+    #[test]
+    fn reports_failed_output_as_runtime_error() {
+        for trace in [false, true] {
+            let config = VmConfig::new().trace(trace).output(Box::new(FailingOutput));
+            let mut vm = VM::new(config);
+            assert_eq!(vm.interpret(arithmetic()), InterpretResult::RuntimeError);
+            if trace {
+                // Tracing fails on the very first instruction.
+                assert_eq!(vm.location(), None);
+                assert_eq!(vm.stack_values(), [1.2]);
+            }
+        }
     }
-}
 
-impl Instruction for Divide {
-    fn marshal(_chunk: &Chunk, _offset: usize) -> (usize, Self) {
-        return (1, Divide {});
+    #[test]
+    fn writes_errors_to_the_configured_sink() {
+        let errors = Output::default();
+        let config = VmConfig::new()
+            .trace(false)
+            .arithmetic_policy(ArithmeticPolicy::ErrorOnDivisionByZero)
+            .profiling(true)
+            .output(Box::new(io::sink()))
+            .errors(Box::new(errors.clone()));
+        let mut vm = VM::new(config);
+        let divide_by_zero = || {
+            return chunk(|chunk| {
+                One::new().write(chunk, 1);
+                Zero::new().write(chunk, 2);
+                Divide::new().write(chunk, 2);
+                Return::new().write(chunk, 3);
+            });
+        };
+        assert_eq!(
+            vm.interpret(divide_by_zero()),
+            InterpretResult::RuntimeError
+        );
+        assert_eq!(errors.text(), "Division by zero.\n[line 2] in script\n");
+
+        let mut named = divide_by_zero();
+        named.set_file("main.lox");
+        vm.interpret(named);
+        assert!(errors
+            .text()
+            .ends_with("Division by zero.\n[main.lox:2] in script\n"));
+
+        let lone_add = chunk(|chunk| {
+            Add::new().write(chunk, 1);
+            Return::new().write(chunk, 1);
+        });
+        assert_eq!(vm.interpret(lone_add), InterpretResult::CompileError);
+        assert!(errors.text().ends_with("0000: stack underflow\n"));
+
+        let before = errors.text().len();
+        vm.close();
+        assert_eq!(errors.text()[before..], vm.profile().unwrap().report());
+
+        // A sink that can't be written to doesn't change how the VM finishes.
+        let config = VmConfig::new()
+            .trace(false)
+            .profiling(true)
+            .output(Box::new(io::sink()))
+            .errors(Box::new(FailingOutput));
+        let mut vm = VM::new(config);
+        let no_return = chunk(|chunk| One::new().write(chunk, 1));
+        assert_eq!(vm.interpret(no_return), InterpretResult::RuntimeError);
+        vm.close();
     }
 
-    fn unmarshal(&self, chunk: &mut Chunk, line: Line) {
-        chunk.instructions.push(DIVIDE);
-        chunk.lines.push(line);
+    #[test]
+    fn restore_rejects_unknown_flags() {
+        let mut vm = VM::new(VmConfig::new().trace(false).fuel(100));
+        vm.load(arithmetic()).unwrap();
+        vm.run_for(2);
+        let snapshot = vm.snapshot();
+        // The snapshot ends with the paused flag, the fuel flag, and the
+        // remaining fuel.
+        let paused = snapshot.len() - 10;
+        let fuel = snapshot.len() - 9;
+        assert_eq!(snapshot[paused], 1);
+        assert_eq!(snapshot[fuel], 1);
+        for offset in [paused, fuel] {
+            for value in [2, 255] {
+                let mut corrupted = snapshot.clone();
+                corrupted[offset] = value;
+                assert!(matches!(
+                    VM::restore(&corrupted, VmConfig::new()),
+                    Err(LoadError::Format)
+                ));
+            }
+        }
     }
 
-    fn disassemble(&self) -> String {
-        "DIVIDE".to_string()
+    #[test]
+    fn continue_pauses_on_breakpoints() {
+        let mut in_file = arithmetic();
+        in_file.set_file("main.lox");
+        let in_file = Arc::new(in_file);
+        let mut vm = VM::new(VmConfig::new().trace(false).output(Box::new(io::sink())));
+        vm.set_breakpoint("main.lox", 1);
+        vm.set_breakpoint("main.lox", 3);
+
+        vm.load(Arc::clone(&in_file)).unwrap();
+        assert_eq!(vm.continue_(), StepOutcome::Paused);
+        assert_eq!(vm.location(), Some((0, 1)));
+        assert_eq!(vm.stack_values(), []);
+        assert_eq!(vm.continue_(), StepOutcome::Paused);
+        assert_eq!(vm.location(), Some((9, 3)));
+        assert_eq!(vm.stack_values().len(), 1);
+        // Line 3 doesn't pause again on its later instructions.
+        assert_eq!(vm.continue_(), StepOutcome::Done);
+
+        // Stepping always makes progress, even onto or over a breakpoint.
+        vm.load(Arc::clone(&in_file)).unwrap();
+        assert_eq!(vm.step(), StepOutcome::Paused);
+        assert_eq!(vm.location(), Some((2, 1)));
+        assert_eq!(vm.run_for(100), StepOutcome::Done);
+
+        vm.clear_breakpoint("main.lox", 1);
+        vm.clear_breakpoint("main.lox", 3);
+        vm.load(Arc::clone(&in_file)).unwrap();
+        assert_eq!(vm.continue_(), StepOutcome::Done);
+
+        // Breakpoints belong to a file, so a chunk without one never hits
+        // them.
+        vm.set_breakpoint("main.lox", 3);
+        vm.load(arithmetic()).unwrap();
+        assert_eq!(vm.continue_(), StepOutcome::Done);
     }
-}
 
-pub struct Constant {
-    value: f64,
-}
+    /// Logs every call it receives, tagged with its name, to a shared log.
+    struct Recorder {
+        name: &'static str,
+        log: Arc<std::sync::Mutex<Vec<String>>>,
+    }
 
-impl Constant {
-    pub fn new(value: f64) -> Constant {
-        return Constant { value: value };
+    impl Recorder {
+        fn record(
+            &self,
+            when: &str,
+            offset: usize,
+            line: Line,
+            inst: &DecodedInstruction,
+            stack: &[Value],
+        ) {
+            let entry = format!(
+                "{} {} {:0>4} {} {} {:?}",
+                self.name,
+                when,
+                offset,
+                line,
+                inst.disassemble(),
+                stack
+            );
+            self.log.lock().unwrap().push(entry);
+        }
     }
 
-    pub fn write(&self, chunk: &mut Chunk, line: Line) {
-        self.unmarshal(chunk, line);
+    impl VmHook for Recorder {
+        fn before_instruction(
+            &mut self,
+            offset: usize,
+            line: Line,
+            inst: &DecodedInstruction,
+            stack: &[Value],
+        ) {
+            self.record("before", offset, line, inst, stack);
+        }
+
+        fn after_instruction(
+            &mut self,
+            offset: usize,
+            line: Line,
+            inst: &DecodedInstruction,
+            stack: &[Value],
+        ) {
+            self.record("after", offset, line, inst, stack);
+        }
     }
-}
 
-impl Instruction for Constant {
-    fn marshal(chunk: &Chunk, offset: usize) -> (usize, Self) {
-        let loc = chunk.instructions[offset + 1];
-        return (
-            2,
-            Constant {
-                value: chunk.pool.get(loc.into()),
-            },
+    #[test]
+    fn hooks_observe_every_instruction_in_order() {
+        let log = Arc::new(std::sync::Mutex::new(Vec::new()));
+        let mut vm = VM::new(VmConfig::new().trace(false).output(Box::new(io::sink())));
+        for name in ["first", "second"] {
+            let log = Arc::clone(&log);
+            vm.add_hook(Box::new(Recorder { name, log }));
+        }
+        let result = vm.interpret(chunk(|chunk| {
+            chunk.write_constant(2.0, 1);
+            AddOne::new().write(chunk, 1);
+            Return::new().write(chunk, 2);
+        }));
+        assert_eq!(result, InterpretResult::Ok);
+        assert_eq!(
+            *log.lock().unwrap(),
+            [
+                "first before 0000 1 CONSTANT: 2 []",
+                "second before 0000 1 CONSTANT: 2 []",
+                "first after 0000 1 CONSTANT: 2 [2.0]",
+                "second after 0000 1 CONSTANT: 2 [2.0]",
+                "first before 0002 1 ADD_ONE [2.0]",
+                "second before 0002 1 ADD_ONE [2.0]",
+                "first after 0002 1 ADD_ONE [3.0]",
+                "second after 0002 1 ADD_ONE [3.0]",
+                "first before 0003 2 RETURN [3.0]",
+                "second before 0003 2 RETURN [3.0]",
+                "first after 0003 2 RETURN []",
+                "second after 0003 2 RETURN []",
+            ]
         );
     }
 
-    fn unmarshal(&self, chunk: &mut Chunk, line: Line) {
-        chunk.instructions.push(CONSTANT);
-        chunk.instructions.push(chunk.pool.add(self.value) as u8);
-        chunk.lines.push(line);
-        chunk.lines.push(0);
-    }
+    #[test]
+    fn profile_attributes_time_to_lines() {
+        let config = VmConfig::new().trace(false).profiling(true);
+        let mut vm = VM::new(config.output(Box::new(io::sink())));
+        vm.interpret(arithmetic());
+        let profile = vm.profile().unwrap();
+
+        let lines: Vec<Line> = profile.lines().iter().map(|&(line, _)| line).collect();
+        assert_eq!(lines, [1, 2, 3, 4]);
+        let mut counts: Vec<(&str, u64)> = profile
+            .entries()
+            .iter()
+            .map(|&(name, count, _)| (name, count))
+            .collect();
+        counts.sort();
+        assert_eq!(
+            counts,
+            [
+                ("ADD", 1),
+                ("ADD_ONE", 1),
+                ("CONSTANT", 3),
+                ("DIVIDE", 1),
+                ("DUP", 1),
+                ("MULTIPLY", 1),
+                ("NEGATE", 1),
+                ("RETURN", 1),
+                ("SUBTRACT_CONSTANT", 1),
+            ]
+        );
 
-    fn disassemble(&self) -> String {
-        format!("CONSTANT: {}", self.value)
+        // Weights are the nanoseconds reported by `lines`.
+        let expected: String = profile
+            .lines()
+            .iter()
+            .map(|(line, time)| format!("script;line {} {}\n", line, time.as_nanos()))
+            .collect();
+        assert_eq!(profile.collapsed(), expected);
     }
 }